@@ -1,36 +1,128 @@
 use crate::db_backend::SQLite;
-use crate::purge::purge;
+use crate::remove::{removal_plan, remove, RemoveOptions};
+use crate::utils::confirm;
+use rusqlite::Connection;
+use std::path::Path;
 
-pub fn autoremove(db_name: &str) {
-    let mut conn = SQLite::init(db_name);
-    let tx = conn.transaction().unwrap();
-    let mut get_removeable_stmt = tx
-        .prepare(
-            "WITH RECURSIVE auto_installed_packages as (
-                SELECT * FROM status as s WHERE s.auto_installed = 1
-            ), removable_packages as (
-                SELECT DISTINCT s.package
-                FROM auto_installed_packages as s LEFT JOIN dependencies as d ON s.package = d.dependency
-                WHERE d.dependency IS NULL
-                UNION
-                SELECT d.dependency
-                FROM dependencies as d, removable_packages as rd, auto_installed_packages as s
-                WHERE s.package = d.dependency AND d.package = rd.package
-            )
-            SELECT * FROM removable_packages",
-        )
+/// Returns the automatically installed packages which are no longer needed, each after the
+/// packages needing it. A dependency is only removable if every installed package needing it is
+/// removable as well, so `remove` accepts all of them in this order.
+pub fn removable_packages(conn: &Connection) -> Vec<String> {
+    let mut stmt = conn
+        .prepare("SELECT package FROM installed WHERE auto_installed = 1 ORDER BY package")
         .unwrap();
-
-    let deps = get_removeable_stmt
-        .query_map([], |p| p.get::<_, String>(0))
+    let auto_installed: Vec<String> = stmt
+        .query_map([], |r| r.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    let mut stmt = conn
+        .prepare("SELECT package, dependency FROM needed_dependencies")
+        .unwrap();
+    let needed: Vec<(String, String)> = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
         .unwrap()
-        .map(|p| p.unwrap())
-        .collect::<Vec<String>>();
-    get_removeable_stmt.finalize().unwrap();
+        .map(|r| r.unwrap())
+        .collect();
+
+    let mut removable: Vec<String> = vec![];
+    loop {
+        let next: Vec<String> = auto_installed
+            .iter()
+            .filter(|p| !removable.contains(p))
+            .filter(|p| {
+                needed
+                    .iter()
+                    .filter(|(_, dependency)| dependency == *p)
+                    .all(|(package, _)| package == *p || removable.contains(package))
+            })
+            .cloned()
+            .collect();
+        if next.is_empty() {
+            return removable;
+        }
+        removable.extend(next);
+    }
+}
+
+pub fn autoremove(db_name: &str, root: &Path, options: &RemoveOptions) {
+    let mut conn = SQLite::init(db_name);
+    let tx = conn.transaction().unwrap();
+    let deps = removable_packages(&tx);
+    if deps.is_empty() {
+        println!("Nothing to remove");
+        return;
+    }
+    let plan = removal_plan(&tx, &deps);
     tx.commit().unwrap();
+    if options.simulate {
+        plan.print();
+        return;
+    }
+    plan.print_summary();
+    if !options.assume_yes && !confirm("Do you want to continue?") {
+        println!("Abort.");
+        return;
+    }
 
+    let options = RemoveOptions {
+        purge: options.purge,
+        assume_yes: true,
+        quiet: true,
+        ..Default::default()
+    };
     for d in deps {
         println!("Remove {}", d);
-        purge(db_name, d.as_str());
+        remove(db_name, root, d.as_str(), &options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::populate::{populate_db, populate_db_auto_installed, setup_db};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn stanza(package: &str, depends: Option<&str>) -> String {
+        format!(
+            "Package: {}\nStatus: install ok installed\nVersion: 1.0\nArchitecture: all\n\
+            Maintainer: Test <test@example.com>\n{}Description: {}\n\n",
+            package,
+            depends
+                .map(|d| format!("Depends: {}\n", d))
+                .unwrap_or_default(),
+            package
+        )
+    }
+
+    #[test]
+    fn shared_dependencies_stay_installed() {
+        let dir = tempdir().unwrap();
+        let db = dir.path().join("packages.db");
+        let db_name = db.to_str().unwrap();
+        let status = [
+            stanza("app", Some("libfoo")),
+            stanza("alt", Some("libfoo")),
+            stanza("libfoo", None),
+            stanza("unused", Some("libbar")),
+            stanza("libbar", None),
+        ]
+        .concat();
+        fs::write(dir.path().join("status"), status).unwrap();
+        let auto_installed: String = ["alt", "libfoo", "unused", "libbar"]
+            .iter()
+            .map(|p| format!("Package: {}\nAuto-Installed: 1\n\n", p))
+            .collect();
+        fs::write(dir.path().join("extended_states"), auto_installed).unwrap();
+        setup_db(db_name, "");
+        populate_db(db_name, &dir.path().join("status"), "");
+        populate_db_auto_installed(
+            db_name,
+            dir.path().join("extended_states").display().to_string(),
+        );
+
+        let conn = Connection::open(&db).unwrap();
+        assert_eq!(removable_packages(&conn), ["alt", "unused", "libbar"]);
     }
 }
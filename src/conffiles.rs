@@ -0,0 +1,309 @@
+use crate::utils::{ask, md5_file};
+use rusqlite::{params, Transaction};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How to handle conffiles which were modified locally and changed by the package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConffilePolicy {
+    /// Ask the user, keeping the current version without a terminal
+    #[default]
+    Ask,
+    /// Keep the current version and store the new one as `.dpkg-new`
+    KeepOld,
+    /// Install the new version and store the current one as `.dpkg-old`
+    InstallNew,
+    /// Take the default action, which is keeping the current version
+    Default,
+}
+
+impl ConffilePolicy {
+    /// Chooses the policy from the `--confold`, `--confnew` and `--confdef` flags. Like dpkg,
+    /// `--confdef` takes precedence.
+    pub fn from_flags(confold: bool, confnew: bool, confdef: bool) -> ConffilePolicy {
+        if confdef {
+            ConffilePolicy::Default
+        } else if confold {
+            ConffilePolicy::KeepOld
+        } else if confnew {
+            ConffilePolicy::InstallNew
+        } else {
+            ConffilePolicy::Ask
+        }
+    }
+}
+
+/// Returns the paths listed in a `conffiles` control file. Like dpkg, a path may be preceded by
+/// flags such as `remove-on-upgrade`.
+pub fn parse_conffiles(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|c| c.find('/').map(|i| c[i..].trim().to_string()))
+        .collect()
+}
+
+/// Returns the path of a conffile below the root directory
+pub fn conffile_path(root: &Path, conffile: &str) -> PathBuf {
+    root.join(conffile.trim_start_matches('.').trim_start_matches('/'))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Path the shipped version of a conffile is unpacked to
+pub fn new_conffile_path(root: &Path, conffile: &str) -> PathBuf {
+    with_suffix(&conffile_path(root, conffile), ".dpkg-new")
+}
+
+enum Action {
+    /// Move the new version in place
+    Install,
+    /// Keep the current file and drop the new version
+    Keep,
+    /// Move the new version in place and keep the current file as `.dpkg-old`
+    Replace,
+    /// Keep the current file and leave the new version as `.dpkg-new`
+    KeepBoth,
+}
+
+/// Installs the unpacked conffiles of the package. Based on the hash of the previously configured
+/// version, the newly shipped version and the file on disk, the new version either replaces the
+/// current file or stays beside it, like dpkg does. The hash of the new version is recorded.
+pub fn resolve_conffiles(tx: &Transaction, root: &Path, package: &str, policy: ConffilePolicy) {
+    let mut stmt = tx
+        .prepare("SELECT conffile, hash FROM conffiles WHERE package = ?1")
+        .unwrap();
+    let conffiles: Vec<(String, Option<String>)> = stmt
+        .query_map(params![package], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+
+    for (conffile, old_hash) in conffiles {
+        let path = conffile_path(root, &conffile);
+        let new_path = new_conffile_path(root, &conffile);
+        if !new_path.exists() {
+            continue;
+        }
+        let new_hash = md5_file(&new_path);
+        let current_hash = md5_file(&path);
+
+        let action = match &current_hash {
+            // Conffiles deleted by the user stay deleted
+            None if old_hash.is_some() => Action::Keep,
+            None => Action::Install,
+            Some(current) if Some(current) == new_hash.as_ref() => Action::Keep,
+            // Unmodified by the user
+            Some(current) if Some(current) == old_hash.as_ref() => Action::Install,
+            // Modified by the user, but unchanged by the package
+            Some(_) if old_hash.is_some() && old_hash == new_hash => Action::Keep,
+            Some(_) => {
+                let replace = match policy {
+                    ConffilePolicy::InstallNew => true,
+                    ConffilePolicy::KeepOld | ConffilePolicy::Default => false,
+                    ConffilePolicy::Ask => ask(
+                        &format!(
+                            "Configuration file {} was modified, but the package ships a new \
+                            version. Install the package maintainer's version?",
+                            conffile
+                        ),
+                        false,
+                    ),
+                };
+                if replace {
+                    Action::Replace
+                } else {
+                    Action::KeepBoth
+                }
+            }
+        };
+
+        match action {
+            Action::Install => fs::rename(&new_path, &path).unwrap(),
+            Action::Keep => fs::remove_file(&new_path).unwrap(),
+            Action::Replace => {
+                let old_path = with_suffix(&path, ".dpkg-old");
+                println!(
+                    "Installing new version of {}, the current one is saved as {}",
+                    conffile,
+                    old_path.display()
+                );
+                fs::rename(&path, &old_path).unwrap();
+                fs::rename(&new_path, &path).unwrap();
+            }
+            Action::KeepBoth => println!(
+                "Keeping modified {}, the new version is saved as {}",
+                conffile,
+                new_path.display()
+            ),
+        }
+        tx.execute(
+            "UPDATE conffiles SET hash = ?3 WHERE package = ?1 AND conffile = ?2",
+            params![package, conffile, new_hash],
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md5::{Digest, Md5};
+    use rusqlite::Connection;
+    use tempfile::tempdir;
+
+    /// The files left after resolving /etc/foo.conf
+    #[derive(Debug, PartialEq)]
+    struct Outcome {
+        current: Option<String>,
+        dpkg_new: Option<String>,
+        dpkg_old: Option<String>,
+    }
+
+    fn outcome(current: Option<&str>, dpkg_new: Option<&str>, dpkg_old: Option<&str>) -> Outcome {
+        Outcome {
+            current: current.map(|c| c.to_string()),
+            dpkg_new: dpkg_new.map(|c| c.to_string()),
+            dpkg_old: dpkg_old.map(|c| c.to_string()),
+        }
+    }
+
+    /// Resolves a conffile whose previously configured version was `old`, which is `current` on
+    /// disk and which the package now ships as `new`
+    fn resolve(
+        old: Option<&str>,
+        current: Option<&str>,
+        new: &str,
+        policy: ConffilePolicy,
+    ) -> Outcome {
+        let root = tempdir().unwrap();
+        let path = conffile_path(root.path(), "/etc/foo.conf");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        if let Some(current) = current {
+            fs::write(&path, current).unwrap();
+        }
+        fs::write(new_conffile_path(root.path(), "/etc/foo.conf"), new).unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE conffiles (package TEXT, conffile TEXT, hash TEXT)",
+            [],
+        )
+        .unwrap();
+        let old_hash = old.map(|o| format!("{:x}", Md5::digest(o.as_bytes())));
+        conn.execute(
+            "INSERT INTO conffiles VALUES ('foo', '/etc/foo.conf', ?1)",
+            params![old_hash],
+        )
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        resolve_conffiles(&tx, root.path(), "foo", policy);
+        let hash: String = tx
+            .query_row("SELECT hash FROM conffiles", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(hash, format!("{:x}", Md5::digest(new.as_bytes())));
+
+        let read = |suffix: &str| fs::read_to_string(with_suffix(&path, suffix)).ok();
+        Outcome {
+            current: read(""),
+            dpkg_new: read(".dpkg-new"),
+            dpkg_old: read(".dpkg-old"),
+        }
+    }
+
+    #[test]
+    fn new_conffiles_are_installed() {
+        assert_eq!(
+            resolve(None, None, "new", ConffilePolicy::Ask),
+            outcome(Some("new"), None, None)
+        );
+    }
+
+    #[test]
+    fn deleted_conffiles_stay_deleted() {
+        assert_eq!(
+            resolve(Some("old"), None, "new", ConffilePolicy::Ask),
+            outcome(None, None, None)
+        );
+    }
+
+    #[test]
+    fn identical_conffiles_are_kept() {
+        assert_eq!(
+            resolve(Some("old"), Some("new"), "new", ConffilePolicy::Ask),
+            outcome(Some("new"), None, None)
+        );
+    }
+
+    #[test]
+    fn unmodified_conffiles_are_updated() {
+        assert_eq!(
+            resolve(Some("old"), Some("old"), "new", ConffilePolicy::Ask),
+            outcome(Some("new"), None, None)
+        );
+    }
+
+    #[test]
+    fn modified_conffiles_unchanged_by_the_package_are_kept() {
+        assert_eq!(
+            resolve(Some("old"), Some("mine"), "old", ConffilePolicy::Ask),
+            outcome(Some("mine"), None, None)
+        );
+    }
+
+    #[test]
+    fn modified_and_changed_conffiles_follow_the_policy() {
+        let keep = outcome(Some("mine"), Some("new"), None);
+        assert_eq!(
+            resolve(Some("old"), Some("mine"), "new", ConffilePolicy::KeepOld),
+            keep
+        );
+        assert_eq!(
+            resolve(Some("old"), Some("mine"), "new", ConffilePolicy::Default),
+            keep
+        );
+        assert_eq!(
+            resolve(Some("old"), Some("mine"), "new", ConffilePolicy::InstallNew),
+            outcome(Some("new"), None, Some("mine"))
+        );
+    }
+
+    #[test]
+    fn existing_files_of_new_conffiles_follow_the_policy() {
+        assert_eq!(
+            resolve(None, Some("mine"), "new", ConffilePolicy::KeepOld),
+            outcome(Some("mine"), Some("new"), None)
+        );
+    }
+
+    #[test]
+    fn flagged_conffiles() {
+        assert_eq!(
+            parse_conffiles("/etc/foo.conf\n\nremove-on-upgrade /etc/bar.conf \n"),
+            ["/etc/foo.conf", "/etc/bar.conf"]
+        );
+    }
+
+    #[test]
+    fn confdef_takes_precedence() {
+        assert_eq!(
+            ConffilePolicy::from_flags(true, true, true),
+            ConffilePolicy::Default
+        );
+        assert_eq!(
+            ConffilePolicy::from_flags(true, true, false),
+            ConffilePolicy::KeepOld
+        );
+        assert_eq!(
+            ConffilePolicy::from_flags(false, true, false),
+            ConffilePolicy::InstallNew
+        );
+        assert_eq!(
+            ConffilePolicy::from_flags(false, false, false),
+            ConffilePolicy::Ask
+        );
+    }
+}
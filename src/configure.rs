@@ -0,0 +1,140 @@
+use crate::conffiles::ConffilePolicy;
+use crate::db_backend::SQLite;
+use crate::install::configure;
+use crate::plan::{order_steps, Step};
+use crate::resolve::Edge;
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Returns the packages whose installation or removal didn't finish, with their version and state
+fn broken_packages(conn: &Connection) -> Vec<(String, String, String, String)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT package, version, IFNULL(flag, 'ok'), IFNULL(state, '') FROM status
+            WHERE IFNULL(flag, 'ok') != 'ok' OR
+                  state IN ('half-installed', 'unpacked', 'half-configured', 'triggers-awaited',
+                            'triggers-pending')
+            ORDER BY package",
+        )
+        .unwrap();
+    let packages = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+        .unwrap()
+        .map(|p| p.unwrap())
+        .collect();
+    packages
+}
+
+/// Packages which can't be configured anymore, but must be reinstalled
+fn needs_reinstall(flag: &str, state: &str) -> bool {
+    flag == "reinstreq" || state == "half-installed"
+}
+
+/// Reports broken packages like `dpkg --audit`
+pub fn audit(db_name: &str) {
+    let conn = SQLite::init(db_name);
+    let packages = broken_packages(conn.conn.as_ref().unwrap());
+    if packages.is_empty() {
+        println!("All packages are completely installed");
+        return;
+    }
+    let messages = [
+        "The following packages are in a mess due to serious problems during installation. They \
+        must be reinstalled for them to function properly:",
+        "The following packages have been unpacked but not yet configured. They must be \
+        configured using pack configure --pending:",
+        "The following packages are only half configured, probably due to problems configuring \
+        them the first time. The configuration should be retried using pack configure --pending:",
+    ];
+    let group = |flag: &str, state: &str| {
+        if needs_reinstall(flag, state) {
+            0
+        } else if state == "unpacked" {
+            1
+        } else {
+            2
+        }
+    };
+    for (i, message) in messages.iter().enumerate() {
+        let matching: Vec<_> = packages
+            .iter()
+            .filter(|(_, _, flag, state)| group(flag, state) == i)
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+        println!("{}", message);
+        for (package, version, _, _) in matching {
+            println!("  {} {}", package, version);
+        }
+    }
+}
+
+/// Configures all unpacked and half-configured packages in dependency order, like
+/// `dpkg --configure --pending`. Packages requiring a reinstallation are only reported.
+pub fn configure_pending(db_name: &str, root: &Path, policy: ConffilePolicy) {
+    let mut conn = SQLite::init(db_name);
+    let mut pending = vec![];
+    for (package, _, flag, state) in broken_packages(conn.conn.as_ref().unwrap()) {
+        if needs_reinstall(&flag, &state) {
+            println!("{} must be reinstalled", package);
+        } else {
+            pending.push(package);
+        }
+    }
+    if pending.is_empty() {
+        println!("Nothing to configure");
+        return;
+    }
+
+    let edges: Vec<Edge> = {
+        let conn = conn.conn.as_ref().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT package, dependency, type = 'pre-depends' FROM dependencies
+                WHERE type = 'depends' OR type = 'pre-depends'",
+            )
+            .unwrap();
+        let edges = stmt
+            .query_map([], |r| {
+                Ok(Edge {
+                    package: r.get(0)?,
+                    dependency: r.get(1)?,
+                    pre_depends: r.get(2)?,
+                })
+            })
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        edges
+    };
+    let steps = match order_steps(&pending, &edges) {
+        Ok(steps) => steps,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    // Like dpkg, packages depending on a package which failed to configure are skipped
+    let conn = conn.conn.as_mut().unwrap();
+    let mut failed: Vec<String> = vec![];
+    for step in steps {
+        if let Step::Configure(package) = step {
+            if let Some(e) = edges
+                .iter()
+                .find(|e| e.package == package && failed.contains(&e.dependency))
+            {
+                println!(
+                    "Skipping {}, it depends on {} which isn't configured",
+                    package, e.dependency
+                );
+                failed.push(package);
+            } else if let Err(e) = configure(conn, root, &package, policy) {
+                println!("{}", e);
+                println!("Unable to configure {}", package);
+                failed.push(package);
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::db_backend::SQLite;
+use rusqlite::{params, Connection};
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Relations in the order dpkg writes them
+const RELATIONS: [(&str, &str); 9] = [
+    ("replaces", "Replaces"),
+    ("provides", "Provides"),
+    ("depends", "Depends"),
+    ("pre-depends", "Pre-Depends"),
+    ("recommends", "Recommends"),
+    ("suggests", "Suggests"),
+    ("breaks", "Breaks"),
+    ("conflicts", "Conflicts"),
+    ("enhances", "Enhances"),
+];
+
+/// Formats the relations of the given type like in a control file, e.g. `a (>= 1) | b, c`
+fn relation_field(
+    conn: &Connection,
+    suffix: &str,
+    package: &str,
+    version: &str,
+    dep_type: &str,
+) -> Option<String> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT alt_group, TRIM(dependency), IFNULL(version_cmp, ''), IFNULL(version, '')
+            FROM dependencies{} WHERE package = ?1 AND package_version = ?2 AND type = ?3
+            ORDER BY alt_group, rowid",
+            suffix
+        ))
+        .unwrap();
+    let mut groups: Vec<(i64, Vec<String>)> = vec![];
+    let relations = stmt
+        .query_map(params![package, version, dep_type], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+            ))
+        })
+        .unwrap()
+        .map(|r| r.unwrap());
+    for (alt_group, dependency, version_cmp, version) in relations {
+        let relation = if version.is_empty() {
+            dependency
+        } else {
+            format!("{} ({} {})", dependency, version_cmp, version)
+        };
+        match groups.last_mut() {
+            Some((group, alternatives)) if *group == alt_group => alternatives.push(relation),
+            _ => groups.push((alt_group, vec![relation])),
+        }
+    }
+    if groups.is_empty() {
+        return None;
+    }
+    Some(
+        groups
+            .iter()
+            .map(|(_, alternatives)| alternatives.join(" | "))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// Returns the conffiles of the installed package with their hash
+fn get_conffiles(conn: &Connection, package: &str) -> Vec<(String, Option<String>)> {
+    let mut stmt = conn
+        .prepare("SELECT conffile, hash FROM conffiles WHERE package = ?1 ORDER BY rowid")
+        .unwrap();
+    let conffiles = stmt
+        .query_map(params![package], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    conffiles
+}
+
+/// Adds the field to the stanza if it has a value
+fn add(stanza: &mut Vec<String>, name: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        stanza.push(format!("{}: {}", name, value));
+    }
+}
+
+/// Writes or deletes a file of the dpkg info directory
+fn write_info(info_dir: &Path, name: &str, extension: &str, content: &str, executable: bool) {
+    let path = info_dir.join(format!("{}.{}", name, extension));
+    if content.is_empty() {
+        let _ = fs::remove_file(&path);
+        return;
+    }
+    fs::write(&path, content).unwrap();
+    if executable {
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+/// Reconstructs the control stanza of the package version from the `status` table with the given
+/// suffix. Installed packages are described like in the dpkg status file, available ones like in
+/// a Packages index.
+pub fn stanza(conn: &Connection, suffix: &str, package: &str, version: &str) -> Option<String> {
+    let row: Vec<Option<String>> = conn
+        .query_row(
+            &format!(
+                "SELECT IFNULL(want, 'install'), IFNULL(flag, 'ok'), IFNULL(state, 'installed'),
+                    p.priority, section, CAST(installed_size AS TEXT), maintainer_name,
+                    maintainer_mail, architecture, multi_arch, source, config_version,
+                    description, homepage, filename, CAST(size AS TEXT), md5, sha256, essential,
+                    protected, important
+                FROM status{} as s LEFT JOIN priorities as p ON s.priority = p.id
+                WHERE s.package = ?1 AND s.version = ?2",
+                suffix
+            ),
+            params![package, version],
+            |r| (0..21).map(|i| r.get(i)).collect(),
+        )
+        .ok()?;
+    let field = |i: usize| row[i].as_deref().filter(|v| !v.is_empty());
+    let installed = suffix.is_empty();
+
+    let mut stanza = vec![format!("Package: {}", package)];
+    add(&mut stanza, "Essential", field(18));
+    add(&mut stanza, "Protected", field(19));
+    if installed {
+        stanza.push(format!(
+            "Status: {} {} {}",
+            field(0).unwrap(),
+            field(1).unwrap(),
+            field(2).unwrap()
+        ));
+    }
+    add(&mut stanza, "Priority", field(3));
+    add(&mut stanza, "Section", field(4));
+    add(&mut stanza, "Installed-Size", field(5));
+    let maintainer = match (field(6), field(7)) {
+        (Some(name), Some(mail)) => Some(format!("{}<{}>", name, mail)),
+        (name, _) => name.map(|n| n.trim().to_string()),
+    };
+    add(&mut stanza, "Maintainer", maintainer.as_deref());
+    add(&mut stanza, "Architecture", field(8));
+    add(&mut stanza, "Multi-Arch", field(9));
+    add(&mut stanza, "Source", field(10));
+    add(&mut stanza, "Version", Some(version));
+    // dpkg omits the configured version if it is the installed one
+    if installed && field(2) != Some("installed") {
+        add(&mut stanza, "Config-Version", field(11));
+    }
+    for (dep_type, field_name) in &RELATIONS {
+        let relations = relation_field(conn, suffix, package, version, dep_type);
+        add(&mut stanza, field_name, relations.as_deref());
+    }
+    if installed {
+        // Conffiles of unconfigured packages have no hash yet
+        let conffiles: Vec<String> = get_conffiles(conn, package)
+            .iter()
+            .map(|(conffile, hash)| {
+                let hash = hash.as_deref().filter(|h| !h.is_empty());
+                format!(" {} {}", conffile, hash.unwrap_or("newconffile"))
+            })
+            .collect();
+        if !conffiles.is_empty() {
+            stanza.push(format!("Conffiles:\n{}", conffiles.join("\n")));
+        }
+    } else {
+        add(&mut stanza, "Filename", field(14));
+        add(&mut stanza, "Size", field(15));
+        add(&mut stanza, "MD5sum", field(16));
+        add(&mut stanza, "SHA256", field(17));
+    }
+    add(&mut stanza, "Description", field(12));
+    let built_using = relation_field(conn, suffix, package, version, "built-using");
+    add(&mut stanza, "Built-Using", built_using.as_deref());
+    add(&mut stanza, "Homepage", field(13));
+    add(&mut stanza, "Important", field(20));
+    Some(stanza.join("\n"))
+}
+
+/// Writes the installed packages as dpkg status file and info directory, so dpkg and apt can be
+/// used alongside pack
+pub fn export_dpkg(db_name: &str, admindir: &Path) {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    let info_dir = admindir.join("info");
+    fs::create_dir_all(&info_dir).unwrap();
+    // Tells dpkg that the info files of multi-arch packages are qualified with their architecture
+    fs::write(info_dir.join("format"), "1\n").unwrap();
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT package, version, IFNULL(architecture, ''), IFNULL(multi_arch, ''),
+                IFNULL(preinst, ''), IFNULL(prerm, ''), IFNULL(postinst, ''), IFNULL(postrm, '')
+            FROM status WHERE IFNULL(state, '') != 'not-installed'
+            ORDER BY package",
+        )
+        .unwrap();
+    let packages: Vec<Vec<String>> = stmt
+        .query_map([], |r| (0..8).map(|i| r.get(i)).collect())
+        .unwrap()
+        .map(|p| p.unwrap())
+        .collect();
+
+    let mut status = String::new();
+    for package in &packages {
+        let (name, version, architecture, multi_arch) =
+            (&package[0], &package[1], &package[2], &package[3]);
+        status.push_str(&stanza(conn, "", name, version).unwrap());
+        status.push_str("\n\n");
+
+        // Info files of multi-arch packages are qualified with their architecture
+        let info_name = match multi_arch.as_str() {
+            "same" => format!("{}:{}", name, architecture),
+            _ => name.to_string(),
+        };
+        let conffiles = get_conffiles(conn, name);
+        let files: Vec<(String, Option<String>)> = {
+            let mut stmt = conn
+                .prepare("SELECT file, md5 FROM installed_files WHERE package = ?1")
+                .unwrap();
+            let files = stmt
+                .query_map(params![name], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(|f| f.unwrap())
+                .collect();
+            files
+        };
+        // Like dpkg, list the parent directories as well
+        let mut list = BTreeSet::new();
+        let mut md5sums = BTreeSet::new();
+        for (file, md5) in &files {
+            let path = file.trim_start_matches('.').to_string();
+            for parent in Path::new(&path).ancestors().skip(1) {
+                if parent != Path::new("/") {
+                    list.insert(parent.to_str().unwrap().to_string());
+                }
+            }
+            // Conffiles are checked with their hash in the status file instead
+            if let Some(md5) = md5 {
+                if !conffiles.iter().any(|(c, _)| *c == path) {
+                    md5sums.insert(format!("{}  {}", md5, path.trim_start_matches('/')));
+                }
+            }
+            list.insert(path);
+        }
+        let lines = |set: BTreeSet<String>| set.into_iter().map(|l| l + "\n").collect::<String>();
+        let list = if list.is_empty() {
+            String::new()
+        } else {
+            format!("/.\n{}", lines(list))
+        };
+        // dpkg expects both files for every package, even if empty
+        fs::write(info_dir.join(format!("{}.list", info_name)), list).unwrap();
+        fs::write(
+            info_dir.join(format!("{}.md5sums", info_name)),
+            lines(md5sums),
+        )
+        .unwrap();
+        let conffiles: String = conffiles.iter().map(|(c, _)| format!("{}\n", c)).collect();
+        write_info(&info_dir, &info_name, "conffiles", &conffiles, false);
+        for (i, script) in ["preinst", "prerm", "postinst", "postrm"]
+            .iter()
+            .enumerate()
+        {
+            write_info(&info_dir, &info_name, script, &package[4 + i], true);
+        }
+    }
+
+    // Replace the status file atomically, like dpkg does
+    let status_path = admindir.join("status");
+    let new_path = admindir.join("status-new");
+    fs::write(&new_path, status).unwrap();
+    if status_path.exists() {
+        fs::copy(&status_path, admindir.join("status-old")).unwrap();
+    }
+    fs::rename(&new_path, &status_path).unwrap();
+    println!(
+        "Exported {} packages to {}",
+        packages.len(),
+        admindir.display()
+    );
+}
@@ -1,66 +1,88 @@
+use crate::conffiles::{new_conffile_path, parse_conffiles, resolve_conffiles, ConffilePolicy};
 use crate::db_backend::SQLite;
+use crate::journal;
+use crate::plan::{order_steps, Plan, Step};
 use crate::populate::{populate_db, setup_db};
-use crate::utils::execute_script;
-use crate::MIRROR;
-use debpkg::DebPkg;
+use crate::remove::{get_conffiles, remove, RemoveOptions};
+use crate::resolve::{
+    broken_dependents, find_clashes, get_candidate, get_installed_version, resolve_dependencies,
+    Relation,
+};
+use crate::state::{get_state, restore_state, set_state};
+use crate::utils::{
+    confirm, download, execute_script, execute_stored_script, native_architecture, normalize_path,
+    ScriptContext,
+};
+use crate::verify::{parse_md5sums, FileAttributes};
+use deb_version::compare_versions;
+use debpkg::{Control, DebPkg};
 use log::warn;
-use reqwest::Url;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Transaction};
 use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::sync::{Arc, RwLock};
-use std::thread;
+use std::path::{Component, Path};
 use tar::EntryType;
-use tempfile::{tempdir, NamedTempFile};
+use tempfile::{tempdir, NamedTempFile, TempDir};
 
-fn get_package(package: &str, conn: &mut Connection) -> Result<DebPkg<File>, std::io::ErrorKind> {
+fn get_package(
+    package: &str,
+    version: &str,
+    conn: &mut Connection,
+) -> Result<DebPkg<File>, std::io::ErrorKind> {
     if package.ends_with(".deb") {
         let source = File::open(package).expect("File not found");
         Ok(DebPkg::parse(source).expect("Parsing failed"))
     } else {
+        // The same version may be available from several origins. The origin added first wins,
+        // packages imported without origin can only be installed from another one.
         let tx = conn.transaction().unwrap();
-        let (filename, _expected_md5, expected_sha256) = tx
+        let (filename, _expected_md5, expected_sha256, origin) = tx
             .query_row(
-                "SELECT filename, md5, sha256 FROM status_available WHERE package = ?1",
-                params![package.trim()],
+                "SELECT filename, md5, sha256, origin FROM status_available
+                WHERE package = ?1 AND version = ?2 AND (architecture = 'all' OR architecture = ?3)
+                ORDER BY origin = '', rowid LIMIT 1",
+                params![package.trim(), version, native_architecture()],
                 |r| {
                     Ok((
                         r.get::<_, String>(0),
                         r.get::<_, String>(1),
                         r.get::<_, String>(2),
+                        r.get::<_, String>(3)?,
                     ))
                 },
             )
             .unwrap();
         tx.commit().unwrap();
 
-        if filename.is_err() {
-            warn!("{}", filename.unwrap_err());
-            return Err(std::io::ErrorKind::NotFound);
-        }
-
-        println!("Download {}", package);
-        let url = Url::parse(MIRROR)
-            .unwrap()
-            .join(&filename.unwrap())
-            .unwrap();
+        let filename = match filename {
+            Ok(filename) => filename,
+            Err(e) => {
+                warn!("{}", e);
+                return Err(std::io::ErrorKind::NotFound);
+            }
+        };
 
-        let p = reqwest::blocking::get(url).unwrap();
-        if !p.status().is_success() {
-            warn!("Download of package failed. Status: {}", p.status());
+        println!("Download {} {}", package, version);
+        if origin.is_empty() {
+            warn!("The origin of {} {} is unknown", package, version);
             return Err(std::io::ErrorKind::NotFound);
         }
+        let url = format!("{}{}", origin, filename);
+        let content = match download(&url) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("{}", e);
+                return Err(std::io::ErrorKind::NotFound);
+            }
+        };
 
         // Check hash
         let mut sha256 = Sha256::new();
-        let content = p.bytes().unwrap();
-        sha256.update(content.as_ref());
-        let hash = sha256.finalize();
-        let mut hash_str = String::new();
-        hash.as_slice()
-            .iter()
-            .for_each(|c| hash_str.push_str(&*format!("{:02x}", c)));
+        sha256.update(&content);
+        let hash_str = format!("{:x}", sha256.finalize());
         if hash_str == expected_sha256.unwrap() {
             println!("{}: Hashes match", package);
         } else {
@@ -73,204 +95,1042 @@ fn get_package(package: &str, conn: &mut Connection) -> Result<DebPkg<File>, std
         let mut writer = NamedTempFile::new().unwrap();
         let reader = writer.reopen().unwrap();
 
-        writer.write_all(content.as_ref()).unwrap();
+        writer.write_all(&content).unwrap();
 
         Ok(DebPkg::parse(reader).expect("Parsing failed"))
     }
 }
 
-struct State {
-    rwlock: RwLock<i32>,
+#[derive(Default)]
+pub struct InstallOptions {
+    /// Remove installed packages which conflict with the planned ones instead of aborting
+    pub remove_conflicts: bool,
+    /// Providers to choose for virtual packages if there are several candidates
+    pub preferred: Vec<String>,
+    /// Allow replacing installed packages with older versions
+    pub allow_downgrade: bool,
+    /// Only print the planned actions without changing the system
+    pub simulate: bool,
+    /// Don't ask for confirmation
+    pub assume_yes: bool,
+    /// Handling of locally modified conffiles
+    pub conffile_policy: ConffilePolicy,
 }
 
-fn install_impl(db_name: &str, package_name: String, automatic_install: bool, state: Arc<State>) {
-    let mut conn = SQLite::init(db_name);
+/// A downloaded package archive together with its extracted control files
+struct Archive {
+    package: DebPkg<File>,
+    control_dir: TempDir,
+    version: String,
+    architecture: String,
+    automatic_install: bool,
+    /// Installed version, if the archive replaces it
+    old_version: Option<String>,
+}
+
+fn open_archive(
+    package_name: &str,
+    version: &str,
+    conn: &mut SQLite,
+    automatic_install: bool,
+) -> Option<Archive> {
+    let mut package = get_package(package_name, version, conn.conn.as_mut().unwrap()).ok()?;
+    let control_dir = tempdir().unwrap();
+    package
+        .control()
+        .unwrap()
+        .unpack(control_dir.path())
+        .unwrap();
+    let control = Control::parse(File::open(control_dir.path().join("control")).unwrap()).unwrap();
+    let name = control.name().to_string();
+    let version = control.version().to_string();
+    let architecture = control.get("Architecture").unwrap_or("all").to_string();
+    let installed = conn
+        .conn
+        .as_ref()
+        .unwrap()
+        .query_row(
+            "SELECT version, auto_installed FROM installed WHERE package = ?1",
+            params![name],
+            |r| Ok((r.get::<_, String>(0)?, r.get::<_, Option<bool>>(1)?)),
+        )
+        .ok();
+    Some(Archive {
+        package,
+        control_dir,
+        version,
+        architecture,
+        // Upgrades keep the installation reason
+        automatic_install: match &installed {
+            Some((_, auto)) => auto.unwrap_or(false),
+            None => automatic_install,
+        },
+        old_version: installed.map(|(v, _)| v),
+    })
+}
 
-    if !automatic_install {
-        // Check whether package is already installed
-        let mut installed_stmt = conn
-            .prepare("SELECT count(*) FROM status WHERE package = ?1".to_string())
+fn read_script(control_dir: &Path, name: &str) -> String {
+    let path = control_dir.join(name);
+    let mut script = String::new();
+    if path.exists() {
+        File::open(path)
+            .unwrap()
+            .read_to_string(&mut script)
             .unwrap();
+    }
+    script
+}
+
+/// Reconfigures the old version after a failed upgrade. If that fails as well, the old version
+/// is left half-configured.
+fn abort_upgrade(
+    conn: &Connection,
+    ctx: &ScriptContext,
+    postinst: &str,
+    version: &str,
+    previous: &Option<String>,
+) {
+    match execute_stored_script(ctx, "postinst", postinst, &["abort-upgrade", version]) {
+        Ok(()) => restore_state(conn, ctx.package, previous.as_deref()),
+        Err(_) => set_state(conn, "status", ctx.package, "install ok half-configured"),
+    }
+}
+
+/// Unwinds an unpacking after the pre-install script of the new version ran. The old version of an
+/// upgrade, given with its post-install script, is reconfigured. A new installation is dropped
+/// again, unless the post-remove script fails, leaving it half-installed.
+fn abort_unpack(
+    conn: &Connection,
+    ctx: &ScriptContext,
+    new_postrm: &Path,
+    upgrade: Option<(&str, &str)>,
+    version: &str,
+    config_files_version: Option<&str>,
+    previous: &Option<String>,
+) {
+    match upgrade {
+        Some((old_version, postinst)) => {
+            let _ = execute_script(ctx, "postrm", new_postrm, &["abort-upgrade", old_version]);
+            abort_upgrade(conn, ctx, postinst, version, previous);
+        }
+        None => {
+            let mut args = vec!["abort-install"];
+            if let Some(version) = config_files_version {
+                args.push(version);
+            }
+            if execute_script(ctx, "postrm", new_postrm, &args).is_ok() {
+                restore_state(conn, ctx.package, previous.as_deref());
+            }
+        }
+    }
+}
+
+/// Returns another installed package owning the file, with its version and the file as recorded
+/// for it
+fn file_owner(
+    conn: &Connection,
+    package_name: &str,
+    file: &str,
+) -> Option<(String, String, String)> {
+    conn.query_row(
+        "SELECT f.package, i.version, f.file FROM installed_files as f, installed as i
+        WHERE f.file = ?2 AND f.package = i.package AND f.package != ?1",
+        params![package_name, file],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )
+    .ok()
+}
+
+/// Checks whether the package to install declares `Replaces` on the given version of the owner
+fn replaces(conn: &Connection, package_name: &str, owner: &str, owner_version: &str) -> bool {
+    conn.query_row(
+        "SELECT EXISTS (
+            SELECT * FROM dependencies_temp
+            WHERE TRIM(package) = ?1 AND type = 'replaces' AND TRIM(dependency) = ?2 AND
+                  cmpversion(?3, version_cmp, version)
+        )",
+        params![package_name, owner, owner_version],
+        |r| r.get::<_, bool>(0),
+    )
+    .unwrap()
+}
 
-        let installed = installed_stmt
-            .query_row(params![package_name.trim()], |r| r.get::<_, u64>(0))
+/// Unpacks the package. Failing maintainer scripts are unwound like dpkg does, so the package
+/// either keeps its previous state or is left half-installed. The package is recorded as
+/// half-installed while its files are replaced and as unpacked afterwards.
+fn unpack(
+    conn: &mut Connection,
+    root: &Path,
+    package_name: &str,
+    archive: &mut Archive,
+) -> Result<(), String> {
+    println!("Unpack {}", package_name);
+    let ctx = ScriptContext {
+        package: package_name,
+        architecture: &archive.architecture,
+        root,
+    };
+
+    let old_files: Vec<String> = {
+        let mut stmt = conn
+            .prepare("SELECT file FROM installed_files WHERE package = ?1")
             .unwrap();
+        let files = stmt
+            .query_map(params![package_name], |r| r.get::<_, String>(0))
+            .unwrap()
+            .map(|f| f.unwrap())
+            .collect();
+        files
+    };
 
-        installed_stmt.finalize().unwrap();
+    // Packages installed for the first time are recorded before anything is changed
+    let previous = get_state(conn, package_name);
+    // Configuration files of a removed version are reported to the pre-install script
+    let config_files_version = conn
+        .query_row(
+            "SELECT version FROM status WHERE package = ?1 AND state = 'config-files'",
+            params![package_name],
+            |r| r.get::<_, String>(0),
+        )
+        .ok();
+    journal::begin(conn, package_name, "unpack", previous.as_deref());
+    if previous.is_none() {
+        conn.execute(
+            "INSERT INTO status SELECT * FROM status_temp WHERE package = ?1",
+            params![package_name],
+        )
+        .unwrap();
+    }
+    set_state(
+        conn,
+        "status",
+        package_name,
+        "install reinstreq half-installed",
+    );
 
-        if installed > 0 {
-            println!("Package {} already installed", package_name);
-            return;
+    // Run pre-remove script of the old version and pre-install script of the new version
+    let control_dir = archive.control_dir.path();
+    let preinst = control_dir.join("preinst");
+    let new_postrm = control_dir.join("postrm");
+    let mut old_scripts = None;
+    if let Some(old_version) = &archive.old_version {
+        let (prerm, postinst, postrm) = conn
+            .query_row(
+                "SELECT IFNULL(prerm, ''), IFNULL(postinst, ''), IFNULL(postrm, '') FROM status
+                WHERE package = ?1",
+                params![package_name],
+                |r| {
+                    Ok((
+                        r.get::<_, String>(0)?,
+                        r.get::<_, String>(1)?,
+                        r.get::<_, String>(2)?,
+                    ))
+                },
+            )
+            .unwrap();
+        if let Err(e) = execute_stored_script(&ctx, "prerm", &prerm, &["upgrade", &archive.version])
+        {
+            // Like dpkg, give the script of the new version a chance
+            let args = ["failed-upgrade", old_version.as_str()];
+            if execute_script(&ctx, "prerm", &control_dir.join("prerm"), &args).is_err() {
+                abort_upgrade(conn, &ctx, &postinst, &archive.version, &previous);
+                journal::cleanup(conn, root, package_name);
+                return Err(e);
+            }
         }
-        setup_db(db_name, "_temp");
+        old_scripts = Some((old_version.clone(), postinst, postrm));
+    }
+    let upgrade = old_scripts
+        .as_ref()
+        .map(|(version, postinst, _)| (version.as_str(), postinst.as_str()));
+    let mut args = match upgrade {
+        Some((old_version, _)) => vec!["upgrade", old_version],
+        None => vec!["install"],
+    };
+    if let Some(version) = &config_files_version {
+        args.push(version);
+    }
+    if let Err(e) = execute_script(&ctx, "preinst", &preinst, &args) {
+        abort_unpack(
+            conn,
+            &ctx,
+            &new_postrm,
+            upgrade,
+            &archive.version,
+            config_files_version.as_deref(),
+            &previous,
+        );
+        journal::cleanup(conn, root, package_name);
+        return Err(e);
     }
 
-    let package = get_package(package_name.as_str(), &mut conn.conn.as_mut().unwrap());
-    if package.is_err() {
-        return;
+    // Copy files
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // adapted from tar/src/archive.rs
+
+    // Delay any directory entries until the end (they will be created if needed by
+    // descendants), to ensure that directory permissions do not interfer with descendant
+    // extraction. Every file is recorded in the journal before it gets replaced.
+    let conffiles = parse_conffiles(&read_script(control_dir, "conffiles"));
+    let md5sums = parse_md5sums(&read_script(control_dir, "md5sums"));
+    let mut new_files = HashMap::new();
+    let mut replaced_files = Vec::new();
+    let mut conflict = None;
+    let mut directories = Vec::new();
+    let mut data = archive.package.data().unwrap();
+    for entry in data.entries().unwrap() {
+        let mut file = entry.unwrap();
+        if file.header().entry_type() == EntryType::Directory {
+            directories.push(file);
+            continue;
+        }
+        let path = normalize_path(file.path().unwrap().to_str().unwrap());
+        if Path::new(&path)
+            .components()
+            .any(|c| c == Component::ParentDir)
+        {
+            warn!("Skipping {} outside of the root directory", path);
+            continue;
+        }
+        // Conffiles are installed on configuration, once it is known whether they were
+        // modified locally
+        let is_conffile = conffiles.iter().any(|c| c == path.trim_start_matches('.'));
+        let target = if is_conffile {
+            new_conffile_path(root, &path)
+        } else {
+            root.join(path.trim_start_matches('.').trim_start_matches('/'))
+        };
+        // Like dpkg, files of other packages are only overwritten if they are replaced
+        if let Some((owner, owner_version, owner_file)) = file_owner(conn, package_name, &path) {
+            if !replaces(conn, package_name, &owner, &owner_version) {
+                conflict = Some(format!(
+                    "Trying to overwrite {}, which is also in package {} {}",
+                    path.trim_start_matches('.'),
+                    owner,
+                    owner_version
+                ));
+                break;
+            }
+            if !replaced_files.iter().any(|(p, _)| *p == owner) {
+                println!(
+                    "Replacing files in old package {} ({})",
+                    owner, owner_version
+                );
+            }
+            replaced_files.push((owner, owner_file));
+        }
+        journal::replace_file(conn, root, package_name, &mut file, &target).unwrap();
+        let md5 = md5sums.get(path.trim_start_matches('.').trim_start_matches('/'));
+        let mut attributes = FileAttributes::read(&target, md5);
+        // Conffiles may keep the locally modified version, so only their hash is known
+        if is_conffile {
+            attributes = FileAttributes {
+                md5: attributes.md5,
+                ..Default::default()
+            };
+        }
+        new_files.insert(path, attributes);
+    }
+    if let Some(e) = conflict {
+        journal::rollback(conn, package_name);
+        abort_unpack(
+            conn,
+            &ctx,
+            &new_postrm,
+            upgrade,
+            &archive.version,
+            config_files_version.as_deref(),
+            &previous,
+        );
+        journal::cleanup(conn, root, package_name);
+        return Err(e);
+    }
+    for mut dir in directories {
+        dir.unpack_in(root).unwrap();
     }
 
-    let mut package = package.unwrap();
-    let control_dir = tempdir().unwrap();
-    package
-        .control()
-        .unwrap()
-        .unpack(control_dir.path())
+    // end from archive.rs
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+
+    // Files dropped by the new version. Like dpkg, obsolete conffiles are kept.
+    let old_conffiles = get_conffiles(conn, package_name);
+    if archive.old_version.is_some() {
+        for file in old_files.iter().filter(|f| !new_files.contains_key(*f)) {
+            if !old_conffiles
+                .iter()
+                .any(|c| c == file.trim_start_matches('.'))
+            {
+                let path = root.join(file.trim_start_matches('.').trim_start_matches('/'));
+                println!("Remove: {}", path.display());
+                journal::delete_file(conn, package_name, &path);
+            }
+        }
+    }
+
+    let tx = conn.transaction().unwrap();
+    // Also drops the conffiles left by a removed version
+    tx.execute(
+        "DELETE FROM installed_files WHERE package = ?1",
+        params![package_name],
+    )
+    .unwrap();
+    for (file, attributes) in &new_files {
+        tx.execute(
+            "INSERT INTO installed_files (package, file, md5, mode, uid, gid, size)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                package_name,
+                file,
+                attributes.md5,
+                attributes.mode,
+                attributes.uid,
+                attributes.gid,
+                attributes.size
+            ],
+        )
+        .unwrap();
+    }
+    // Replaced files now belong to the new package
+    for (owner, file) in &replaced_files {
+        tx.execute(
+            "DELETE FROM installed_files WHERE package = ?1 AND file = ?2",
+            params![owner, file],
+        )
+        .unwrap();
+        tx.execute(
+            "DELETE FROM conffiles WHERE package = ?1 AND conffile = ?2",
+            params![owner, file.trim_start_matches('.')],
+        )
+        .unwrap();
+    }
+
+    tx.execute(
+        "DELETE FROM conffiles_temp WHERE package = ?1",
+        params![package_name],
+    )
+    .unwrap();
+    for conffile in &conffiles {
+        tx.execute(
+            "INSERT INTO conffiles_temp (package, conffile) VALUES (?1, ?2)",
+            params![package_name, conffile],
+        )
+        .unwrap();
+    }
+
+    // Run post-remove script of the old version
+    let mut status = "install ok unpacked";
+    let mut result = Ok(());
+    if let Some((old_version, _, postrm)) = &old_scripts {
+        if let Err(e) =
+            execute_stored_script(&ctx, "postrm", postrm, &["upgrade", &archive.version])
+        {
+            let args = ["failed-upgrade", old_version.as_str()];
+            if execute_script(&ctx, "postrm", &new_postrm, &args).is_err() {
+                status = "install reinstreq half-installed";
+                result = Err(e);
+            }
+        }
+    }
+
+    // Store the maintainer scripts, which are needed to remove the package, to unwind a failed
+    // upgrade or to export the package to dpkg. The version configured last is kept until the
+    // new one gets configured.
+    tx.execute(
+        "UPDATE status_temp SET auto_installed = ?2, preinst = ?6, prerm = ?3, postinst = ?4,
+        postrm = ?5,
+        config_version = (SELECT config_version FROM status WHERE package = ?1)
+        WHERE package = ?1",
+        params![
+            package_name,
+            archive.automatic_install as i32,
+            read_script(control_dir, "prerm"),
+            read_script(control_dir, "postinst"),
+            read_script(control_dir, "postrm"),
+            read_script(control_dir, "preinst")
+        ],
+    )
+    .unwrap();
+    tx.execute(
+        "REPLACE INTO status SELECT * FROM status_temp WHERE package = ?1",
+        params![package_name],
+    )
+    .unwrap();
+    set_state(&tx, "status", package_name, status);
+    tx.execute(
+        "DELETE FROM dependencies WHERE package = ?1",
+        params![package_name],
+    )
+    .unwrap();
+    tx.execute(
+        "INSERT INTO dependencies SELECT * FROM dependencies_temp WHERE package = ?1",
+        params![package_name],
+    )
+    .unwrap();
+    // Conffiles keep the hash of the configured version until the new one gets configured
+    tx.execute(
+        "DELETE FROM conffiles WHERE package = ?1 AND conffile NOT IN
+        (SELECT conffile FROM conffiles_temp WHERE package = ?1)",
+        params![package_name],
+    )
+    .unwrap();
+    tx.execute(
+        "INSERT INTO conffiles (package, conffile) SELECT package, conffile FROM conffiles_temp
+        WHERE package = ?1 AND conffile NOT IN (SELECT conffile FROM conffiles WHERE package = ?1)",
+        params![package_name],
+    )
+    .unwrap();
+    journal::commit(&tx, package_name);
+    tx.commit().unwrap();
+    journal::cleanup(conn, root, package_name);
+    result
+}
+
+/// Configures an unpacked package: installs its conffiles and runs its post-install script. The
+/// package is recorded as half-configured until the script succeeded.
+pub fn configure(
+    conn: &mut Connection,
+    root: &Path,
+    package_name: &str,
+    policy: ConffilePolicy,
+) -> Result<(), String> {
+    println!("Configure {}", package_name);
+    let (architecture, config_version, postinst) = conn
+        .query_row(
+            "SELECT IFNULL(architecture, ''), IFNULL(config_version, ''), IFNULL(postinst, '')
+            FROM status WHERE package = ?1",
+            params![package_name],
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .unwrap();
+    set_state(conn, "status", package_name, "install ok half-configured");
+
+    let tx = conn.transaction().unwrap();
+    resolve_conffiles(&tx, root, package_name, policy);
+
+    // Run post-install script with the version configured before
+    let ctx = ScriptContext {
+        package: package_name,
+        architecture: &architecture,
+        root,
+    };
+    let mut args = vec!["configure"];
+    if !config_version.is_empty() {
+        args.push(&config_version);
+    }
+    let result = execute_stored_script(&ctx, "postinst", &postinst, &args);
+    if result.is_ok() {
+        set_state(&tx, "status", package_name, "install ok installed");
+        tx.execute(
+            "UPDATE status SET config_version = version WHERE package = ?1",
+            params![package_name],
+        )
         .unwrap();
+    }
+    tx.commit().unwrap();
+    result
+}
+
+/// Parses a package argument like `foo`, `foo=1.2-3`, `foo>=1.2` or `foo/bookworm` into the
+/// requested relation and release
+fn parse_package_arg(arg: &str) -> (Relation, Option<&str>) {
+    let (arg, release) = match arg.split_once('/') {
+        Some((name, release)) => (name, Some(release)),
+        None => (arg, None),
+    };
+    let (name, version_cmp, version) = match arg.find(|c| "<>=".contains(c)) {
+        Some(i) => {
+            let (name, constraint) = arg.split_at(i);
+            let version = constraint.trim_start_matches(|c| "<>=".contains(c));
+            let version_cmp = &constraint[..constraint.len() - version.len()];
+            (name, Some(version_cmp), Some(version))
+        }
+        None => (arg, None, None),
+    };
+    let relation = Relation {
+        package: "command line".to_string(),
+        dep_type: "depends".to_string(),
+        dependency: name.trim().to_string(),
+        version_cmp: version_cmp.map(|c| c.to_string()),
+        version: version.map(|v| v.trim().to_string()),
+    };
+    (relation, release)
+}
+
+pub fn install(db_name: &str, root: &Path, package_name: String, options: &InstallOptions) {
+    let mut conn = SQLite::init(db_name);
+
+    // Select the version to install
+    let mut version = String::new();
+    if !package_name.ends_with(".deb") {
+        let (relation, release) = parse_package_arg(&package_name);
+        version = match get_candidate(conn.conn.as_ref().unwrap(), &relation, release) {
+            Some(version) => version,
+            None => {
+                println!("No installable version of {} found", package_name);
+                return;
+            }
+        };
+        let installed = get_installed_version(conn.conn.as_ref().unwrap(), &relation.dependency);
+        if let Some(installed) = installed {
+            // Without an explicit version, installed packages are only upgraded
+            if compare_versions(&version, &installed) == Ordering::Equal
+                || (relation.version.is_none()
+                    && compare_versions(&version, &installed) == Ordering::Less)
+            {
+                println!("Package {} already installed", relation.dependency);
+                return;
+            }
+        }
+    }
+
+    // Leftovers of an aborted installation must not leak into this one
+    drop_temp_tables(&mut conn);
+    setup_db(db_name, "_temp");
+
+    let name = if package_name.ends_with(".deb") {
+        package_name.as_str()
+    } else {
+        package_name
+            .split(|c| "<>=/".contains(c))
+            .next()
+            .unwrap()
+            .trim()
+    };
+
+    // Packages from repositories are described by their metadata, so nothing is downloaded
+    // before the whole transaction was confirmed
+    if !package_name.ends_with(".deb") {
+        let old_version = get_installed_version(conn.conn.as_ref().unwrap(), name);
+        if !check_version_change(&package_name, &version, old_version.as_ref(), options) {
+            return;
+        }
+        let tx = conn.transaction().unwrap();
+        add_available_to_temp(&tx, name, &version);
+        tx.commit().unwrap();
+        if !install_with_archives(db_name, root, &mut conn, HashMap::new(), &[], options) {
+            println!("Unable to install {}", package_name);
+        }
+        return;
+    }
 
+    let archive = match open_archive(name, &version, &mut conn, false) {
+        Some(archive) => archive,
+        None => return,
+    };
+    if !check_version_change(
+        &package_name,
+        &archive.version,
+        archive.old_version.as_ref(),
+        options,
+    ) {
+        return;
+    }
     populate_db(
         db_name,
-        control_dir.path().join("control").as_path(),
+        archive.control_dir.path().join("control").as_path(),
         "_temp",
     );
+    // The argument may be a file name, so use the name from the control file
+    let name = conn
+        .conn
+        .as_ref()
+        .unwrap()
+        .query_row("SELECT package FROM status_temp", [], |r| {
+            r.get::<_, String>(0)
+        })
+        .unwrap();
 
-    // Check which dependencies need to get installed
-    if !automatic_install {
-        let mut get_depends_stmt = conn
-            .prepare(
-                "WITH RECURSIVE deps as (
-                    SELECT TRIM(dependency) as dependency, version_cmp, version
-                    FROM dependencies_temp
-                    WHERE type = 'depends'
-                    UNION
-                    SELECT TRIM(d.dependency) as dependency, d.version_cmp, d.version
-                    FROM dependencies_available as d, deps as dr
-                    WHERE TRIM(d.package) = dr.dependency AND
-                          (d.type = 'depends' OR d.type = 'pre-depends')
-                )
-                SELECT DISTINCT d.dependency
-                FROM deps as d
-                WHERE
-                    NOT EXISTS (SELECT * FROM status as s WHERE TRIM(s.package) = d.dependency) AND
-                    NOT EXISTS (SELECT * FROM dependencies as di WHERE di.type = 'provides' AND d.dependency = di.dependency)"
-                    .to_string(),
-            )
-            .unwrap();
-        let deps: Vec<String> = get_depends_stmt
-            .query_map(params![], |p| p.get::<_, String>(0))
-            .unwrap()
-            .map(|p| p.unwrap())
-            .collect();
+    let mut archives = HashMap::new();
+    archives.insert(name, archive);
+    if !install_with_archives(db_name, root, &mut conn, archives, &[], options) {
+        println!("Unable to install {}", package_name);
+    }
+}
+
+/// Checks whether the installed version may be replaced by the given one
+fn check_version_change(
+    package_name: &str,
+    version: &str,
+    old_version: Option<&String>,
+    options: &InstallOptions,
+) -> bool {
+    if let Some(old_version) = old_version {
+        match compare_versions(version, old_version) {
+            Ordering::Equal => {
+                println!("Package {} already installed", package_name);
+                return false;
+            }
+            Ordering::Less if !options.allow_downgrade => {
+                println!(
+                    "Installing {} would downgrade it from {}. Use --allow-downgrade",
+                    package_name, old_version
+                );
+                return false;
+            }
+            _ => {}
+        }
+    }
+    true
+}
 
-        get_depends_stmt.finalize().unwrap();
+/// Copies the metadata of an available package into the temporary tables
+pub fn add_available_to_temp(tx: &Transaction, package: &str, version: &str) {
+    tx.execute(
+        "INSERT INTO status_temp SELECT * FROM status_available
+        WHERE package = ?1 AND version = ?2 AND (architecture = 'all' OR architecture = ?3)
+        ORDER BY rowid LIMIT 1",
+        params![package, version, native_architecture()],
+    )
+    .unwrap();
+    tx.execute(
+        "INSERT INTO dependencies_temp SELECT * FROM dependencies_available WHERE package = ?1 AND package_version = ?2",
+        params![package, version],
+    )
+    .unwrap();
+    tx.execute(
+        "INSERT INTO conffiles_temp SELECT * FROM conffiles_available
+        WHERE package = ?1 AND package_version = ?2",
+        params![package, version],
+    )
+    .unwrap();
+}
 
-        let mut threads = vec![];
-        for d in deps {
-            let state_ref = Arc::clone(&state);
-            threads.push(thread::spawn(move || {
-                println!("Install {}", d);
-                install_impl("packages.db", d, true, state_ref);
-            }));
+/// Collects the affected packages, downloads and the disk usage change of the planned
+/// installation
+fn make_plan(
+    conn: &Connection,
+    planned: &[(String, String)],
+    steps: Vec<Step>,
+    removals: &[String],
+    archives: &HashMap<String, Archive>,
+    kept_back: &[String],
+) -> Plan {
+    let mut plan = Plan {
+        steps,
+        versions: planned.iter().cloned().collect(),
+        kept_back: kept_back.to_vec(),
+        ..Default::default()
+    };
+    for (package, version) in planned {
+        let (size, installed_size) = conn
+            .query_row(
+                "SELECT IFNULL(size, 0), IFNULL(installed_size, 0) FROM status_available
+                WHERE package = ?1 AND version = ?2 AND (architecture = 'all' OR architecture = ?3)
+                ORDER BY origin = '', rowid LIMIT 1",
+                params![package, version, native_architecture()],
+                |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .unwrap_or((0, 0));
+        // Packages given as archive file are described by the temporary tables only
+        let installed_size = conn
+            .query_row(
+                "SELECT installed_size FROM status_temp WHERE package = ?1 AND version = ?2",
+                params![package, version],
+                |r| r.get::<_, Option<i64>>(0),
+            )
+            .unwrap_or(None)
+            .unwrap_or(installed_size);
+        let old = conn
+            .query_row(
+                "SELECT version, IFNULL(installed_size, 0) FROM installed WHERE package = ?1",
+                params![package],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .ok();
+        match old {
+            Some((old_version, old_installed_size)) => {
+                plan.installed_size_delta += installed_size - old_installed_size;
+                plan.upgrades
+                    .push((package.clone(), old_version, version.clone()));
+            }
+            None => {
+                plan.installed_size_delta += installed_size;
+                plan.new.push((package.clone(), version.clone()));
+            }
         }
-        for t in threads {
-            t.join().unwrap();
+        if !archives.contains_key(package) {
+            plan.downloads
+                .push((package.clone(), version.clone(), size));
         }
     }
+    for package in removals {
+        let (version, installed_size) = conn
+            .query_row(
+                "SELECT version, IFNULL(installed_size, 0) FROM status WHERE package = ?1",
+                params![package],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .unwrap();
+        plan.installed_size_delta -= installed_size;
+        plan.removals.push((package.to_string(), version));
+    }
+    plan
+}
 
-    let tx = conn.transaction().unwrap();
+/// Installs the packages stored in `status_temp` together with their dependencies. Packages
+/// which are already installed are upgraded in place.
+pub fn install_packages(
+    db_name: &str,
+    root: &Path,
+    conn: &mut SQLite,
+    kept_back: &[String],
+    options: &InstallOptions,
+) -> bool {
+    install_with_archives(db_name, root, conn, HashMap::new(), kept_back, options)
+}
+
+fn install_with_archives(
+    db_name: &str,
+    root: &Path,
+    conn: &mut SQLite,
+    mut archives: HashMap<String, Archive>,
+    kept_back: &[String],
+    options: &InstallOptions,
+) -> bool {
+    let roots = get_roots(conn.conn.as_ref().unwrap());
 
-    {
-        let _lock = state.rwlock.write();
-        // Install dependencies and package in topological order
+    // Check which dependencies need to get installed
+    let resolution =
+        match resolve_dependencies(conn.conn.as_ref().unwrap(), &roots, &options.preferred) {
+            Ok(resolution) => resolution,
+            Err(e) => {
+                println!("{}", e);
+                drop_temp_tables(conn);
+                return false;
+            }
+        };
 
-        // Run pre-install script
-        let pre_install_script = control_dir.path().join("preinst");
-        if pre_install_script.exists() {
-            execute_script("pre-install", pre_install_script.as_path()).unwrap();
+    // Check for conflicts before anything gets unpacked
+    let mut planned = roots.clone();
+    planned.extend(
+        resolution
+            .candidates
+            .iter()
+            .map(|c| (c.package.clone(), c.version.clone())),
+    );
+    let clashes = find_clashes(conn.conn.as_ref().unwrap(), &planned);
+    let unresolvable = clashes
+        .iter()
+        .any(|c| c.installed.is_none() || !options.remove_conflicts);
+    if unresolvable {
+        println!("Conflicts:");
+        for clash in &clashes {
+            println!("  {}", clash);
         }
+        drop_temp_tables(conn);
+        return false;
+    }
 
-        let mut file_stmt = tx
-            .prepare("INSERT INTO installed_files (package, file) VALUES (?1, ?2)")
-            .unwrap();
+    // Install dependencies and package in topological order
+    let packages: Vec<String> = planned.iter().map(|(p, _)| p.clone()).collect();
+    let versions: HashMap<String, String> = planned.iter().cloned().collect();
+    let roots: Vec<&String> = roots.iter().map(|(p, _)| p).collect();
+    let steps = match order_steps(&packages, &resolution.edges) {
+        Ok(steps) => steps,
+        Err(e) => {
+            println!("{}", e);
+            drop_temp_tables(conn);
+            return false;
+        }
+    };
 
-        // Copy files
-        ////////////////////////////////////////////////////////////////////////////////////////////////
-        // adapted from tar/src/archive.rs
-
-        // Delay any directory entries until the end (they will be created if needed by
-        // descendants), to ensure that directory permissions do not interfer with descendant
-        // extraction.
-        let mut directories = Vec::new();
-        let mut data = package.data().unwrap();
-        for entry in data.entries().unwrap() {
-            let mut file = entry.unwrap();
-            if file.header().entry_type() == EntryType::Directory {
-                directories.push(file);
-            } else {
-                file_stmt
-                    .execute(params![
-                        package_name,
-                        file.path().unwrap().to_str().unwrap()
-                    ])
-                    .unwrap();
-                file.unpack_in("/").unwrap();
-            }
+    // Installed packages must not be left with unfulfilled dependencies by the new versions or
+    // the removal of conflicting packages. When conflicts may be removed, such packages are
+    // removed as well.
+    let mut removals: Vec<String> = clashes.iter().filter_map(|c| c.installed.clone()).collect();
+    removals.sort();
+    removals.dedup();
+    let broken = broken_dependents(conn.conn.as_ref().unwrap(), &removals, &planned);
+    if !broken.is_empty() && !options.remove_conflicts {
+        println!("Unfulfilled dependencies:");
+        for (package, group) in &broken {
+            println!("  {} (installed) depends on {}", package, group);
         }
-        for mut dir in directories {
-            dir.unpack_in("/").unwrap();
+        drop_temp_tables(conn);
+        return false;
+    }
+    removals.extend(broken.into_iter().map(|(package, _)| package));
+
+    for candidate in &resolution.candidates {
+        if let Some(virtual_package) = &candidate.provides {
+            println!(
+                "Selecting {} {} to provide {}",
+                candidate.package, candidate.version, virtual_package
+            );
         }
+    }
 
-        // end from archive.rs
-        ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Everything gets decided before the first download
+    let plan = make_plan(
+        conn.conn.as_ref().unwrap(),
+        &planned,
+        steps,
+        &removals,
+        &archives,
+        kept_back,
+    );
+    if options.simulate {
+        plan.print();
+        drop_temp_tables(conn);
+        return true;
+    }
+    plan.print_summary();
+    if !options.assume_yes && !confirm("Do you want to continue?") {
+        println!("Abort.");
+        drop_temp_tables(conn);
+        return true;
+    }
 
-        file_stmt.finalize().unwrap();
+    for package in &packages {
+        if archives.contains_key(package) {
+            continue;
+        }
+        let automatic_install = !roots.contains(&package);
+        let archive = match open_archive(package, &versions[package], conn, automatic_install) {
+            Some(archive) => archive,
+            None => {
+                drop_temp_tables(conn);
+                return false;
+            }
+        };
+        // Requested packages are already described in the temporary tables
+        if !roots.contains(&package) {
+            populate_db(
+                db_name,
+                archive.control_dir.path().join("control").as_path(),
+                "_temp",
+            );
+        }
+        archives.insert(package.clone(), archive);
+    }
 
-        // Run post-install script
-        let post_install_script = control_dir.path().join("postinst");
-        if post_install_script.exists() {
-            execute_script("post-install", post_install_script.as_path()).unwrap();
+    // Dependents first. The remaining dependencies on removed packages are fulfilled by the
+    // planned ones, which aren't installed yet, so the removal is forced.
+    for package in removals.iter().rev() {
+        println!("Remove {}", package);
+        let options = RemoveOptions {
+            force: true,
+            assume_yes: true,
+            quiet: true,
+            ..Default::default()
+        };
+        if !remove(db_name, root, package, &options) {
+            drop_temp_tables(conn);
+            return false;
         }
     }
 
-    // Store pre- and post-remove scripts
-    let pre_remove_script_path = control_dir.path().join("prerm");
-    let mut pre_remove_script = String::new();
-    if pre_remove_script_path.exists() {
-        File::open(pre_remove_script_path)
-            .unwrap()
-            .read_to_string(&mut pre_remove_script)
-            .unwrap();
+    // Every step records its outcome, so an interrupted installation can be completed later
+    let db = conn.conn.as_mut().unwrap();
+    let mut result = Ok(());
+    for step in plan.steps {
+        result = match step {
+            Step::Unpack(p) => unpack(db, root, &p, archives.get_mut(&p).unwrap()),
+            Step::Configure(p) => configure(db, root, &p, options.conffile_policy),
+        };
+        if let Err(e) = &result {
+            println!("{}", e);
+            break;
+        }
     }
 
-    let post_remove_script_path = control_dir.path().join("postrm");
-    let mut post_remove_script = String::new();
-    if post_remove_script_path.exists() {
-        File::open(post_remove_script_path)
-            .unwrap()
-            .read_to_string(&mut post_remove_script)
-            .unwrap();
+    for (_, archive) in archives {
+        archive.control_dir.close().unwrap();
     }
 
-    tx.execute(
-        "UPDATE status_temp SET auto_installed = ?2, status = 'install ok installed', prerm = ?3, postrm = ?4 WHERE package = ?1",
-        params![package_name, automatic_install as i32, pre_remove_script, post_remove_script],
-    ).unwrap();
+    // Remove temporary data
+    drop_temp_tables(conn);
+    result.is_ok()
+}
 
-    control_dir.close().unwrap();
+/// Returns the packages which were explicitly requested, i.e. are stored in `status_temp`
+fn get_roots(conn: &Connection) -> Vec<(String, String)> {
+    let mut stmt = conn
+        .prepare("SELECT package, version FROM status_temp ORDER BY package")
+        .unwrap();
+    let roots = stmt
+        .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    roots
+}
 
-    if !automatic_install {
-        // Persist info in database
-        tx.execute("INSERT INTO status SELECT * FROM status_temp", [])
+pub fn drop_temp_tables(conn: &mut SQLite) {
+    let tx = conn.transaction().unwrap();
+    for table in &["status_temp", "dependencies_temp", "conffiles_temp"] {
+        tx.execute(&format!("DROP TABLE IF EXISTS {}", table), [])
             .unwrap();
-        tx.execute(
-            "INSERT INTO dependencies SELECT * FROM dependencies_temp",
-            [],
+    }
+    tx.commit().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arg: &str) -> (String, Option<String>, Option<String>, Option<&str>) {
+        let (relation, release) = parse_package_arg(arg);
+        (
+            relation.dependency,
+            relation.version_cmp,
+            relation.version,
+            release,
         )
-        .unwrap();
+    }
 
-        // Remove temporary data
-        tx.execute("DROP TABLE status_temp", []).unwrap();
-        tx.execute("DROP TABLE dependencies_temp", []).unwrap();
-        tx.execute("DROP TABLE conffiles_temp", []).unwrap();
+    #[test]
+    fn plain_names() {
+        assert_eq!(parse("foo"), ("foo".to_string(), None, None, None));
     }
 
-    tx.commit().unwrap();
-}
+    #[test]
+    fn exact_versions() {
+        assert_eq!(
+            parse("foo=1.2-3"),
+            (
+                "foo".to_string(),
+                Some("=".to_string()),
+                Some("1.2-3".to_string()),
+                None
+            )
+        );
+    }
 
-pub fn install(db_name: &str, package_name: String, automatic_install: bool) {
-    let state = State {
-        rwlock: RwLock::new(1),
-    };
-    let arc = Arc::new(state);
-    install_impl(db_name, package_name, automatic_install, arc);
+    #[test]
+    fn version_ranges() {
+        assert_eq!(
+            parse("foo>=1:2.0"),
+            (
+                "foo".to_string(),
+                Some(">=".to_string()),
+                Some("1:2.0".to_string()),
+                None
+            )
+        );
+        assert_eq!(parse("foo<<2").1, Some("<<".to_string()));
+    }
+
+    #[test]
+    fn releases() {
+        assert_eq!(
+            parse("foo/bookworm"),
+            ("foo".to_string(), None, None, Some("bookworm"))
+        );
+        assert_eq!(
+            parse("foo=1.0/bookworm-backports"),
+            (
+                "foo".to_string(),
+                Some("=".to_string()),
+                Some("1.0".to_string()),
+                Some("bookworm-backports")
+            )
+        );
+    }
 }
@@ -0,0 +1,208 @@
+use crate::db_backend::SQLite;
+use crate::install::drop_temp_tables;
+use crate::remove::finish_removal;
+use crate::state::restore_state;
+use rusqlite::{params, Connection};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tar::Entry;
+
+// The journal records every step and filesystem change before it happens. Steps are recorded as
+// rows without path, files with the path they change and the backup of the replaced version.
+// A step row is deleted in the same transaction the result of the step is committed in, so after
+// a crash, steps with row were interrupted and their files are rolled back, or in case of a
+// removal, removed completely.
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Records the start of a step of the package, e.g. `unpack`, `remove` or `purge`. For unpacking,
+/// the state of the package before the step is recorded.
+pub fn begin(conn: &Connection, package: &str, action: &str, previous: Option<&str>) {
+    conn.execute(
+        "INSERT INTO journal (package, action, previous) VALUES (?1, ?2, ?3)",
+        params![package, action, previous],
+    )
+    .unwrap();
+}
+
+/// Marks the step of the package as done. Call within the transaction committing its result.
+pub fn commit(conn: &Connection, package: &str) {
+    conn.execute(
+        "DELETE FROM journal WHERE package = ?1 AND path IS NULL",
+        params![package],
+    )
+    .unwrap();
+}
+
+/// Extracts the entry to the path. The new version is written to a `.pack-new` file and renamed,
+/// so the path always holds a complete version. The replaced version is kept as `.pack-old` until
+/// the step is cleaned up.
+pub fn replace_file<R: Read>(
+    conn: &Connection,
+    root: &Path,
+    package: &str,
+    entry: &mut Entry<R>,
+    path: &Path,
+) -> io::Result<()> {
+    let new_path = with_suffix(path, ".pack-new");
+    let backup = with_suffix(path, ".pack-old");
+    let exists = fs::symlink_metadata(path).is_ok();
+    conn.execute(
+        "INSERT INTO journal (package, action, path, backup) VALUES (?1, 'replace', ?2, ?3)",
+        params![
+            package,
+            path.to_str().unwrap(),
+            if exists { backup.to_str() } else { None }
+        ],
+    )
+    .unwrap();
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    let _ = fs::remove_file(&new_path);
+    match entry.link_name()? {
+        // Hard links refer to another file of the package
+        Some(target) if entry.header().entry_type().is_hard_link() => {
+            let target = target.to_str().unwrap();
+            fs::hard_link(
+                root.join(target.trim_start_matches('.').trim_start_matches('/')),
+                &new_path,
+            )?;
+        }
+        _ => {
+            entry.unpack(&new_path)?;
+        }
+    }
+    if exists {
+        let _ = fs::remove_file(&backup);
+        fs::hard_link(path, &backup).or_else(|_| fs::copy(path, &backup).map(|_| ()))?;
+    }
+    fs::rename(&new_path, path)
+}
+
+/// Removes the file, keeping it as `.pack-old` until the step is cleaned up
+pub fn delete_file(conn: &Connection, package: &str, path: &Path) {
+    let backup = with_suffix(path, ".pack-old");
+    conn.execute(
+        "INSERT INTO journal (package, action, path, backup) VALUES (?1, 'delete', ?2, ?3)",
+        params![package, path.to_str().unwrap(), backup.to_str()],
+    )
+    .unwrap();
+    let _ = fs::rename(path, &backup);
+}
+
+/// Returns the file changes of the package in the order they were made
+fn file_changes(conn: &Connection, package: &str) -> Vec<(String, String, Option<String>)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT action, path, backup FROM journal WHERE package = ?1 AND path IS NOT NULL
+            ORDER BY id",
+        )
+        .unwrap();
+    let changes = stmt
+        .query_map(params![package], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    changes
+}
+
+/// Undoes the file changes of the package in reverse order
+pub fn rollback(conn: &Connection, package: &str) {
+    for (_, path, backup) in file_changes(conn, package).into_iter().rev() {
+        let path = PathBuf::from(path);
+        let _ = fs::remove_file(with_suffix(&path, ".pack-new"));
+        match backup {
+            Some(backup) if Path::new(&backup).exists() => fs::rename(&backup, &path).unwrap(),
+            // Without backup, the file wasn't changed yet
+            Some(_) => {}
+            // The file didn't exist before
+            None => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+/// Deletes the backups of the package and its journal entries. Directories emptied by deleted
+/// files are removed.
+pub fn cleanup(conn: &Connection, root: &Path, package: &str) {
+    for (action, path, backup) in file_changes(conn, package) {
+        if let Some(backup) = backup {
+            let _ = fs::remove_file(&backup);
+        }
+        let parent = Path::new(&path).parent().unwrap();
+        if action == "delete" && parent != root {
+            if let Ok(mut dir) = parent.read_dir() {
+                if dir.next().is_none() {
+                    println!("Remove dir: {}", parent.display());
+                    fs::remove_dir(parent).unwrap();
+                }
+            }
+        }
+    }
+    conn.execute("DELETE FROM journal WHERE package = ?1", params![package])
+        .unwrap();
+}
+
+/// Checks whether the journal holds steps interrupted by a crash
+pub fn is_pending(db_name: &str) -> bool {
+    let db = SQLite::init(db_name);
+    db.conn
+        .as_ref()
+        .unwrap()
+        .query_row("SELECT EXISTS (SELECT * FROM journal)", [], |r| r.get(0))
+        .unwrap()
+}
+
+/// Completes the steps interrupted by a crash. Interrupted unpacking is rolled back, restoring
+/// the previous state of the package, interrupted removals are finished. The temporary tables of
+/// an interrupted installation are dropped.
+pub fn recover(db_name: &str, root: &Path) {
+    let mut db = SQLite::init(db_name);
+    let conn = db.conn.as_mut().unwrap();
+    let steps: Vec<(String, String, Option<String>)> = {
+        let mut stmt = conn
+            .prepare("SELECT package, action, previous FROM journal WHERE path IS NULL ORDER BY id")
+            .unwrap();
+        let steps = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(|s| s.unwrap())
+            .collect();
+        steps
+    };
+    for (package, action, previous) in steps {
+        if action == "unpack" {
+            println!("Rolling back the interrupted installation of {}", package);
+            rollback(conn, &package);
+            restore_state(conn, &package, previous.as_deref());
+        } else {
+            println!("Finishing the interrupted removal of {}", package);
+            finish_removal(conn, root, &package, action == "purge");
+        }
+        cleanup(conn, root, &package);
+    }
+
+    // Steps which were committed before their backups were deleted
+    let packages: Vec<String> = {
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT package FROM journal")
+            .unwrap();
+        let packages = stmt
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|p| p.unwrap())
+            .collect();
+        packages
+    };
+    for package in packages {
+        cleanup(conn, root, &package);
+    }
+    drop_temp_tables(&mut db);
+}
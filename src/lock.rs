@@ -0,0 +1,78 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Exclusive locks on lock files, released when dropped or when the process exits
+pub struct Lock {
+    _files: Vec<File>,
+}
+
+/// Describes the process holding the lock, which writes its PID into the lock file
+fn holder(path: &Path) -> String {
+    let pid = match fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => pid.trim().to_string(),
+        _ => return "another process".to_string(),
+    };
+    match fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        Ok(name) => format!("process {} ({})", pid, name.trim()),
+        Err(_) => format!("process {}", pid),
+    }
+}
+
+fn lock_file(path: &Path, timeout: Duration) -> Result<File, String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| {
+            format!(
+                "Unable to create the lock directory {}: {}",
+                dir.display(),
+                e
+            )
+        })?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("Unable to open the lock file {}: {}", path.display(), e))?;
+
+    let start = Instant::now();
+    let mut waiting = false;
+    while file.try_lock().is_err() {
+        if start.elapsed() >= timeout {
+            return Err(format!(
+                "Could not get the lock {}. It is held by {}",
+                path.display(),
+                holder(path)
+            ));
+        }
+        if !waiting {
+            println!(
+                "Waiting for the lock {} held by {}",
+                path.display(),
+                holder(path)
+            );
+            waiting = true;
+        }
+        sleep(Duration::from_millis(500));
+    }
+
+    // Let waiting processes know who holds the lock
+    file.set_len(0).unwrap();
+    write!(file, "{}", std::process::id()).unwrap();
+    Ok(file)
+}
+
+/// Locks the given files in order, like dpkg locks its `lock-frontend` and `lock` files. Without
+/// timeout, a lock held by another process is an error.
+pub fn acquire(paths: &[PathBuf], timeout: Option<Duration>) -> Result<Lock, String> {
+    let timeout = timeout.unwrap_or_default();
+    let mut files = vec![];
+    for path in paths {
+        files.push(lock_file(path, timeout)?);
+    }
+    Ok(Lock { _files: files })
+}
@@ -1,26 +1,66 @@
 mod autoremove;
+mod conffiles;
+mod configure;
+// The Postgres backend and parts of the SQLite one are not wired up yet
+#[allow(dead_code)]
 mod db_backend;
+mod export;
 mod extract;
 mod install;
+mod journal;
+mod lock;
+mod plan;
 mod populate;
-mod purge;
+mod query;
+mod release;
+mod remove;
+mod resolve;
+mod sources;
+mod state;
 mod update;
+mod upgrade;
 mod utils;
+mod verify;
 
 use crate::autoremove::autoremove;
+use crate::conffiles::ConffilePolicy;
+use crate::configure::{audit, configure_pending};
+use crate::export::export_dpkg;
 use crate::extract::extract_archive;
-use crate::install::install;
-use crate::populate::{populate_db, populate_db_auto_installed, setup_db};
-use crate::purge::purge;
+use crate::install::{install, InstallOptions};
+use crate::journal::{is_pending, recover};
+use crate::lock::acquire;
+use crate::populate::{
+    populate_db_auto_installed, populate_db_from, rebuild_search_index, setup_db,
+};
+use crate::query::{list, policy, search, search_index, show, ListFilter};
+use crate::remove::{remove, RemoveOptions};
 use crate::update::update;
+use crate::upgrade::upgrade;
+use crate::utils::simulation_db;
+use crate::verify::verify;
 use clap::Clap;
-use std::path::Path;
-
-const MIRROR: &str = "http://debian.mirror.lrz.de/debian/";
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Clap)]
 #[clap(name = "pack")]
 struct Cmd {
+    #[clap(
+        long,
+        global = true,
+        about = "Root directory of the installation, also containing the database"
+    )]
+    root: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "Seconds to wait for the lock held by another pack process"
+    )]
+    lock_timeout: Option<u64>,
+
     #[clap(subcommand)]
     sub_command: SubCommand,
 }
@@ -29,10 +69,20 @@ struct Cmd {
 enum SubCommand {
     Extract(Extract),
     Install(Install),
+    Remove(Remove),
     Purge(Purge),
     Populate(Populate),
     Autoremove(Autoremove),
     Update(Update),
+    Upgrade(Upgrade),
+    FullUpgrade(FullUpgrade),
+    Configure(Configure),
+    Verify(Verify),
+    ExportDpkg(ExportDpkg),
+    Show(Show),
+    List(List),
+    Search(Search),
+    Policy(Policy),
 }
 
 #[derive(Clap)]
@@ -45,27 +95,239 @@ struct Extract {
     archive: String,
 }
 
+#[derive(Clap)]
+struct ConffileFlags {
+    #[clap(long, about = "Keep locally modified conffiles")]
+    confold: bool,
+
+    #[clap(long, about = "Install new versions of locally modified conffiles")]
+    confnew: bool,
+
+    #[clap(long, about = "Take the default action for locally modified conffiles")]
+    confdef: bool,
+}
+
+impl ConffileFlags {
+    fn policy(&self) -> ConffilePolicy {
+        ConffilePolicy::from_flags(self.confold, self.confnew, self.confdef)
+    }
+}
+
 #[derive(Clap)]
 #[clap(about = "Installs package")]
 struct Install {
-    #[clap(about = "Package to install")]
+    #[clap(
+        long,
+        about = "Remove installed packages conflicting with or broken by the new ones"
+    )]
+    remove_conflicts: bool,
+
+    #[clap(
+        long,
+        about = "Preferred provider of virtual packages",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    prefer: Vec<String>,
+
+    #[clap(long, about = "Allow installing older versions of installed packages")]
+    allow_downgrade: bool,
+
+    #[clap(flatten)]
+    conffiles: ConffileFlags,
+
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+
+    #[clap(about = "Package to install, optionally as foo=1.2-3, foo>=1.2 or foo/release")]
+    package: String,
+}
+
+#[derive(Clap)]
+#[clap(about = "Removes installed package, keeping its configuration files")]
+struct Remove {
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+
+    #[clap(about = "Package to remove")]
     package: String,
 }
 
 #[derive(Clap)]
-#[clap(about = "Purges installed package")]
+#[clap(about = "Purges installed package including its configuration files")]
 struct Purge {
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+
     #[clap(about = "Package to purge")]
     package: String,
 }
 
 #[derive(Clap)]
 #[clap(about = "Update package metadata")]
-struct Update {}
+struct Update {
+    #[clap(
+        long,
+        about = "Directory containing sources.list, sources.list.d and trusted.gpg.d [default: <root>/etc/apt]"
+    )]
+    config_dir: Option<String>,
+}
+
+#[derive(Clap)]
+#[clap(about = "Upgrade installed packages without installing or removing other packages")]
+struct Upgrade {
+    #[clap(flatten)]
+    conffiles: ConffileFlags,
+
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+}
+
+#[derive(Clap)]
+#[clap(about = "Upgrade installed packages, installing and removing packages if necessary")]
+struct FullUpgrade {
+    #[clap(flatten)]
+    conffiles: ConffileFlags,
+
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+}
+
+#[derive(Clap)]
+#[clap(about = "Completes interrupted installations")]
+struct Configure {
+    #[clap(
+        long,
+        conflicts_with = "audit",
+        about = "Configure all unpacked and half-configured packages"
+    )]
+    pending: bool,
+
+    #[clap(
+        long,
+        about = "Report packages whose installation or removal didn't finish"
+    )]
+    audit: bool,
+
+    #[clap(flatten)]
+    conffiles: ConffileFlags,
+}
+
+#[derive(Clap)]
+#[clap(about = "Checks installed files for modifications")]
+struct Verify {
+    #[clap(about = "Packages to check [default: all installed packages]")]
+    packages: Vec<String>,
+}
+
+#[derive(Clap)]
+#[clap(about = "Writes the installed packages to the dpkg database")]
+struct ExportDpkg {
+    #[clap(
+        long,
+        about = "dpkg database directory to write status and info files to [default: <root>/var/lib/dpkg]"
+    )]
+    admindir: Option<String>,
+}
+
+#[derive(Clap)]
+#[clap(about = "Shows the installed and candidate version of a package")]
+struct Show {
+    #[clap(about = "Package to show")]
+    package: String,
+}
+
+#[derive(Clap)]
+#[clap(about = "Lists packages")]
+struct List {
+    #[clap(
+        long,
+        conflicts_with_all = &["upgradable", "auto", "manual"],
+        about = "Only list installed packages"
+    )]
+    installed: bool,
+
+    #[clap(
+        long,
+        conflicts_with_all = &["auto", "manual"],
+        about = "Only list installed packages with a newer available version"
+    )]
+    upgradable: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "manual",
+        about = "Only list automatically installed packages"
+    )]
+    auto: bool,
+
+    #[clap(long, about = "Only list manually installed packages")]
+    manual: bool,
+}
+
+impl List {
+    fn filter(&self) -> ListFilter {
+        if self.installed {
+            ListFilter::Installed
+        } else if self.upgradable {
+            ListFilter::Upgradable
+        } else if self.auto {
+            ListFilter::Auto
+        } else if self.manual {
+            ListFilter::Manual
+        } else {
+            ListFilter::All
+        }
+    }
+}
+
+#[derive(Clap)]
+#[clap(about = "Searches package names and descriptions")]
+struct Search {
+    #[clap(
+        long,
+        about = "Search the full-text index, supporting \"phrases\" and prefix* queries"
+    )]
+    full_text: bool,
+
+    #[clap(about = "Regular expression to search for, ignoring case, or full-text query")]
+    pattern: String,
+}
+
+#[derive(Clap)]
+#[clap(about = "Shows the installed, candidate and available versions of a package")]
+struct Policy {
+    #[clap(about = "Package to show")]
+    package: String,
+}
 
 #[derive(Clap)]
 #[clap(about = "Autoremove")]
-struct Autoremove {}
+struct Autoremove {
+    #[clap(long, about = "Purge the packages instead of removing them")]
+    purge: bool,
+
+    #[clap(short, long, about = "Only print what would be done")]
+    simulate: bool,
+
+    #[clap(short, long, about = "Don't ask for confirmation")]
+    yes: bool,
+}
 
 #[derive(Clap)]
 #[clap(about = "Populates DB")]
@@ -73,6 +335,13 @@ struct Populate {
     #[clap(short, long, about = "Import available packages")]
     available: bool,
 
+    #[clap(
+        long,
+        requires = "available",
+        about = "Base URL of the archive the available packages are downloaded from"
+    )]
+    origin: Option<String>,
+
     #[clap(short, long, about = "Add information about auto-installed packages")]
     installed: bool,
 
@@ -80,10 +349,89 @@ struct Populate {
     status_file: String,
 }
 
+impl SubCommand {
+    /// Whether the command changes the installation, not only the database
+    fn changes_root(&self) -> bool {
+        match self {
+            SubCommand::Install(i) => !i.simulate,
+            SubCommand::Remove(r) => !r.simulate,
+            SubCommand::Purge(p) => !p.simulate,
+            SubCommand::Autoremove(a) => !a.simulate,
+            SubCommand::Upgrade(u) => !u.simulate,
+            SubCommand::FullUpgrade(u) => !u.simulate,
+            SubCommand::Configure(c) => c.pending,
+            SubCommand::Extract(_)
+            | SubCommand::Populate(_)
+            | SubCommand::Update(_)
+            | SubCommand::Verify(_)
+            | SubCommand::ExportDpkg(_)
+            | SubCommand::Show(_)
+            | SubCommand::List(_)
+            | SubCommand::Search(_)
+            | SubCommand::Policy(_) => false,
+        }
+    }
+
+    /// Whether the command only reads the database
+    fn is_query(&self) -> bool {
+        matches!(
+            self,
+            SubCommand::Extract(_)
+                | SubCommand::Verify(_)
+                | SubCommand::ExportDpkg(_)
+                | SubCommand::Show(_)
+                | SubCommand::List(_)
+                | SubCommand::Search(_)
+                | SubCommand::Policy(_)
+        )
+    }
+}
+
 fn main() {
     env_logger::init();
-    let db_name = "packages.db";
     let cmd = Cmd::parse();
+    // Without an explicit root, the database is kept in the working directory
+    let (root, db_path) = match &cmd.root {
+        Some(root) => {
+            let root = PathBuf::from(root);
+            let db_dir = root.join("var/lib/pack");
+            fs::create_dir_all(&db_dir).unwrap();
+            (root, db_dir.join("packages.db"))
+        }
+        None => (PathBuf::from("/"), PathBuf::from("packages.db")),
+    };
+    let root = root.as_path();
+    let db_name = db_path.to_str().unwrap();
+
+    // Only one process may use the database and change the installation at a time
+    let mut lock_files = vec![];
+    if cmd.sub_command.changes_root() {
+        lock_files.push(root.join("var/lib/pack/lock-frontend"));
+    }
+    lock_files.push(db_path.with_extension("lock"));
+    let _lock = match acquire(&lock_files, cmd.lock_timeout.map(Duration::from_secs)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // A fresh database has no installed packages yet, and no available ones before update
+    setup_db(db_name, "");
+    if !cmd.sub_command.is_query() {
+        setup_db(db_name, "_available");
+    }
+    // Transactions interrupted by a crash are completed before the installation is changed
+    // again. Other commands must not touch the root, it isn't locked for them.
+    if cmd.sub_command.changes_root() {
+        recover(db_name, root);
+    } else if is_pending(db_name) {
+        println!(
+            "An interrupted transaction is pending, it is completed by the next command changing \
+            the installation"
+        );
+    }
     match cmd.sub_command {
         SubCommand::Extract(e) => {
             println!("Extract {}", e.archive);
@@ -92,18 +440,106 @@ fn main() {
         }
         SubCommand::Install(i) => {
             println!("Installing {}", i.package);
-            install(db_name, i.package, false);
+            let options = InstallOptions {
+                remove_conflicts: i.remove_conflicts,
+                preferred: i.prefer,
+                allow_downgrade: i.allow_downgrade,
+                simulate: i.simulate,
+                assume_yes: i.yes,
+                conffile_policy: i.conffiles.policy(),
+            };
+            let (_copy, db_name) = simulation_db(db_name, i.simulate);
+            install(&db_name, root, i.package, &options);
+        }
+        SubCommand::Remove(r) => {
+            println!("Remove {}", r.package);
+            let (_copy, db_name) = simulation_db(db_name, r.simulate);
+            let options = RemoveOptions {
+                simulate: r.simulate,
+                assume_yes: r.yes,
+                ..Default::default()
+            };
+            remove(&db_name, root, &r.package, &options);
         }
         SubCommand::Purge(p) => {
             println!("Purge {}", p.package);
-            purge(db_name, &p.package);
+            let (_copy, db_name) = simulation_db(db_name, p.simulate);
+            let options = RemoveOptions {
+                purge: true,
+                simulate: p.simulate,
+                assume_yes: p.yes,
+                ..Default::default()
+            };
+            remove(&db_name, root, &p.package, &options);
+        }
+        SubCommand::Autoremove(a) => {
+            let (_copy, db_name) = simulation_db(db_name, a.simulate);
+            let options = RemoveOptions {
+                purge: a.purge,
+                simulate: a.simulate,
+                assume_yes: a.yes,
+                ..Default::default()
+            };
+            autoremove(&db_name, root, &options);
+        }
+        SubCommand::Update(u) => {
+            let config_dir = match u.config_dir {
+                Some(config_dir) => PathBuf::from(config_dir),
+                None => root.join("etc/apt"),
+            };
+            update(db_name, &config_dir);
+        }
+        SubCommand::Upgrade(u) => {
+            let options = InstallOptions {
+                simulate: u.simulate,
+                assume_yes: u.yes,
+                conffile_policy: u.conffiles.policy(),
+                ..Default::default()
+            };
+            let (_copy, db_name) = simulation_db(db_name, u.simulate);
+            upgrade(&db_name, root, false, &options);
+        }
+        SubCommand::FullUpgrade(u) => {
+            let options = InstallOptions {
+                simulate: u.simulate,
+                assume_yes: u.yes,
+                conffile_policy: u.conffiles.policy(),
+                ..Default::default()
+            };
+            let (_copy, db_name) = simulation_db(db_name, u.simulate);
+            upgrade(&db_name, root, true, &options);
+        }
+        SubCommand::Configure(c) => {
+            if c.pending {
+                configure_pending(db_name, root, c.conffiles.policy());
+            } else if c.audit {
+                audit(db_name);
+            } else {
+                println!("Use --pending to configure packages or --audit to check them");
+            }
+        }
+        SubCommand::Verify(v) => {
+            if !verify(db_name, root, &v.packages) {
+                std::process::exit(1);
+            }
         }
-        SubCommand::Autoremove(_) => {
-            autoremove(db_name);
+        SubCommand::ExportDpkg(e) => {
+            let admindir = match e.admindir {
+                Some(admindir) => PathBuf::from(admindir),
+                None => root.join("var/lib/dpkg"),
+            };
+            export_dpkg(db_name, &admindir);
         }
-        SubCommand::Update(_) => {
-            update(db_name);
+        SubCommand::Show(s) => show(db_name, &s.package),
+        SubCommand::List(l) => list(db_name, l.filter()),
+        SubCommand::Search(s) => {
+            if s.full_text {
+                search_index(db_name, &s.pattern)
+            } else {
+                search(db_name, &s.pattern)
+            }
         }
+        SubCommand::Policy(p) => policy(db_name, &p.package),
         SubCommand::Populate(p) => {
             println!("Read data from {}", p.status_file);
             if p.installed {
@@ -111,7 +547,19 @@ fn main() {
             } else {
                 let suffix = if p.available { "_available" } else { "" };
                 setup_db(db_name, suffix);
-                populate_db(db_name, Path::new(&p.status_file), suffix);
+                // Filenames of available packages are relative to the archive
+                let origin = match &p.origin {
+                    Some(origin) if !origin.ends_with('/') => format!("{}/", origin),
+                    Some(origin) => origin.clone(),
+                    None => String::new(),
+                };
+                if p.available && origin.is_empty() {
+                    println!("Without --origin, the packages can't be downloaded and installed");
+                }
+                populate_db_from(db_name, Path::new(&p.status_file), suffix, &origin, None);
+                if p.available {
+                    rebuild_search_index(db_name);
+                }
             }
         }
     }
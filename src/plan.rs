@@ -0,0 +1,331 @@
+use crate::resolve::Edge;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A single action of an installation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Run preinst and extract the files
+    Unpack(String),
+    /// Run postinst
+    Configure(String),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Unpack(p) => write!(f, "Unpack {}", p),
+            Step::Configure(p) => write!(f, "Configure {}", p),
+        }
+    }
+}
+
+struct Tarjan<'a> {
+    graph: &'a HashMap<&'a str, Vec<&'a str>>,
+    index: HashMap<&'a str, usize>,
+    low_link: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    on_stack: HashSet<&'a str>,
+    components: Vec<Vec<&'a str>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, node: &'a str) {
+        let index = self.index.len();
+        self.index.insert(node, index);
+        self.low_link.insert(node, index);
+        self.stack.push(node);
+        self.on_stack.insert(node);
+
+        for &next in &self.graph[node] {
+            if !self.index.contains_key(next) {
+                self.visit(next);
+                let low_link = self.low_link[node].min(self.low_link[next]);
+                self.low_link.insert(node, low_link);
+            } else if self.on_stack.contains(next) {
+                let low_link = self.low_link[node].min(self.index[next]);
+                self.low_link.insert(node, low_link);
+            }
+        }
+
+        if self.low_link[node] == self.index[node] {
+            let mut component = vec![];
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.on_stack.remove(member);
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+/// Orders the installation of the packages such that every package is configured after its
+/// dependencies and unpacked after its pre-dependencies are configured.
+///
+/// Like dpkg, dependency cycles are broken by unpacking all members of the cycle before
+/// configuring them. Cycles containing a Pre-Depends cannot be broken and are reported as error.
+pub fn order_steps(packages: &[String], edges: &[Edge]) -> Result<Vec<Step>, String> {
+    let mut graph: HashMap<&str, Vec<&str>> = HashMap::new();
+    for p in packages {
+        graph.insert(p, vec![]);
+    }
+    for e in edges {
+        if graph.contains_key(e.dependency.as_str()) {
+            if let Some(deps) = graph.get_mut(e.package.as_str()) {
+                deps.push(&e.dependency);
+            }
+        }
+    }
+    for deps in graph.values_mut() {
+        deps.sort_unstable();
+        deps.dedup();
+    }
+
+    let mut tarjan = Tarjan {
+        graph: &graph,
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        components: vec![],
+    };
+    let mut nodes: Vec<&str> = graph.keys().copied().collect();
+    nodes.sort_unstable();
+    for node in nodes {
+        if !tarjan.index.contains_key(node) {
+            tarjan.visit(node);
+        }
+    }
+
+    // Tarjan emits components after all components they depend on
+    let mut steps = vec![];
+    for mut component in tarjan.components {
+        component.sort_unstable();
+        if component.len() > 1 {
+            if let Some(e) = edges.iter().find(|e| {
+                e.pre_depends
+                    && component.contains(&e.package.as_str())
+                    && component.contains(&e.dependency.as_str())
+            }) {
+                return Err(format!(
+                    "Dependency cycle between {} contains Pre-Depends of {} on {}",
+                    component.join(", "),
+                    e.package,
+                    e.dependency
+                ));
+            }
+            println!("Breaking dependency cycle: {}", component.join(", "));
+        }
+        steps.extend(component.iter().map(|p| Step::Unpack(p.to_string())));
+        steps.extend(component.iter().map(|p| Step::Configure(p.to_string())));
+    }
+    Ok(steps)
+}
+
+/// Formats a size in bytes like apt does
+pub fn format_size(bytes: i64) -> String {
+    let abs = bytes.abs() as f64;
+    if abs < 1000.0 {
+        format!("{} B", bytes)
+    } else if abs < 1_000_000.0 {
+        format!("{:.1} kB", bytes as f64 / 1000.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+    }
+}
+
+/// The actions of a transaction, used to show what would be done without doing it
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Packages to download as (package, version, size in bytes)
+    pub downloads: Vec<(String, String, i64)>,
+    /// Packages which are not installed yet as (package, version)
+    pub new: Vec<(String, String)>,
+    /// Installed packages replaced by another version as (package, old, new)
+    pub upgrades: Vec<(String, String, String)>,
+    /// Installed packages to remove as (package, version)
+    pub removals: Vec<(String, String)>,
+    /// Upgradable packages which are left alone
+    pub kept_back: Vec<String>,
+    pub steps: Vec<Step>,
+    /// Versions of the packages to unpack
+    pub versions: HashMap<String, String>,
+    /// Change of the disk usage in KiB, based on Installed-Size
+    pub installed_size_delta: i64,
+}
+
+fn print_list(header: &str, mut packages: Vec<String>) {
+    packages.sort();
+    if !packages.is_empty() {
+        println!("{}", header);
+        println!("  {}", packages.join(" "));
+    }
+}
+
+impl Plan {
+    pub fn download_size(&self) -> i64 {
+        self.downloads.iter().map(|(_, _, size)| size).sum()
+    }
+
+    /// Prints an apt-style summary of the transaction
+    pub fn print_summary(&self) {
+        print_list(
+            "The following NEW packages will be installed:",
+            self.new.iter().map(|(p, _)| p.clone()).collect(),
+        );
+        print_list(
+            "The following packages will be upgraded:",
+            self.upgrades
+                .iter()
+                .map(|(p, old, new)| format!("{} ({} -> {})", p, old, new))
+                .collect(),
+        );
+        print_list(
+            "The following packages will be REMOVED:",
+            self.removals.iter().map(|(p, _)| p.clone()).collect(),
+        );
+        print_list(
+            "The following packages have been kept back:",
+            self.kept_back.clone(),
+        );
+        println!(
+            "{} upgraded, {} newly installed, {} to remove and {} not upgraded.",
+            self.upgrades.len(),
+            self.new.len(),
+            self.removals.len(),
+            self.kept_back.len()
+        );
+        if !self.downloads.is_empty() {
+            println!(
+                "Need to get {} of archives.",
+                format_size(self.download_size())
+            );
+        }
+        let delta = self.installed_size_delta * 1024;
+        match delta.cmp(&0) {
+            Ordering::Greater => println!(
+                "After this operation, {} of additional disk space will be used.",
+                format_size(delta)
+            ),
+            Ordering::Less => println!(
+                "After this operation, {} disk space will be freed.",
+                format_size(-delta)
+            ),
+            Ordering::Equal => {}
+        }
+    }
+
+    pub fn print(&self) {
+        for (package, version, size) in &self.downloads {
+            println!(
+                "Download {} ({}) [{}]",
+                package,
+                version,
+                format_size(*size)
+            );
+        }
+        for (package, version) in &self.removals {
+            println!("Remove {} ({})", package, version);
+        }
+        for step in &self.steps {
+            let package = match step {
+                Step::Unpack(p) | Step::Configure(p) => p,
+            };
+            println!("{} ({})", step, self.versions[package]);
+        }
+        println!("Download size: {}", format_size(self.download_size()));
+        let delta = self.installed_size_delta * 1024;
+        println!(
+            "Installed size change: {}{}",
+            if delta > 0 { "+" } else { "" },
+            format_size(delta)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(package: &str, dependency: &str, pre_depends: bool) -> Edge {
+        Edge {
+            package: package.to_string(),
+            dependency: dependency.to_string(),
+            pre_depends,
+        }
+    }
+
+    fn names(packages: &[&str]) -> Vec<String> {
+        packages.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn steps(steps: &[&str]) -> Vec<Step> {
+        steps
+            .iter()
+            .map(|s| match s.split_once(' ').unwrap() {
+                ("Unpack", p) => Step::Unpack(p.to_string()),
+                (_, p) => Step::Configure(p.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let edges = [edge("app", "lib", false), edge("lib", "libc", true)];
+        let order = order_steps(&names(&["app", "lib", "libc"]), &edges).unwrap();
+        assert_eq!(
+            order,
+            steps(&[
+                "Unpack libc",
+                "Configure libc",
+                "Unpack lib",
+                "Configure lib",
+                "Unpack app",
+                "Configure app",
+            ])
+        );
+    }
+
+    #[test]
+    fn dependencies_outside_the_plan_are_ignored() {
+        let edges = [edge("app", "installed", false)];
+        let order = order_steps(&names(&["app"]), &edges).unwrap();
+        assert_eq!(order, steps(&["Unpack app", "Configure app"]));
+    }
+
+    #[test]
+    fn cycles_are_unpacked_before_configuring() {
+        let edges = [
+            edge("a", "b", false),
+            edge("b", "a", false),
+            edge("b", "c", false),
+        ];
+        let order = order_steps(&names(&["a", "b", "c"]), &edges).unwrap();
+        assert_eq!(
+            order,
+            steps(&[
+                "Unpack c",
+                "Configure c",
+                "Unpack a",
+                "Unpack b",
+                "Configure a",
+                "Configure b",
+            ])
+        );
+    }
+
+    #[test]
+    fn cycles_with_pre_depends_fail() {
+        let edges = [edge("a", "b", true), edge("b", "a", false)];
+        let error = order_steps(&names(&["a", "b"]), &edges).err().unwrap();
+        assert_eq!(
+            error,
+            "Dependency cycle between a, b contains Pre-Depends of a on b"
+        );
+    }
+}
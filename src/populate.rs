@@ -1,8 +1,14 @@
+use crate::conffiles::parse_conffiles;
 use crate::db_backend::SQLite;
+use crate::state::{parse_status, set_state};
+use crate::utils::normalize_path;
+use crate::verify::parse_md5sums;
 use debcontrol::{BufParse, Streaming};
-use rusqlite::{params, Statement};
-use std::collections::HashMap;
-use std::fs::File;
+use rusqlite::{params, Connection, Statement, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::iter;
 use std::path::Path;
 
 pub fn populate_db_auto_installed(db_name: &str, file: String) {
@@ -35,9 +41,206 @@ pub fn populate_db_auto_installed(db_name: &str, file: String) {
     tx.commit().unwrap();
 }
 
+/// Version of the database schema, stored as `user_version` of the database
+const SCHEMA_VERSION: i64 = 5;
+
+/// Tables describing the installed packages, kept when the schema changes
+const INSTALLED_TABLES: [&str; 4] = ["status", "dependencies", "conffiles", "installed_files"];
+
+/// Returns the columns of the table
+fn columns(tx: &Transaction, table: &str) -> Vec<String> {
+    let mut stmt = tx
+        .prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+        .unwrap();
+    let columns = stmt
+        .query_map(params![table], |r| r.get(0))
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    columns
+}
+
+/// Returns whether the table exists
+pub fn table_exists(conn: &Connection, table: &str) -> bool {
+    conn.query_row(
+        "SELECT EXISTS (SELECT * FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![table],
+        |r| r.get(0),
+    )
+    .unwrap()
+}
+
+/// Copies the rows of the table of the old schema into the table of the current one. Columns
+/// the old table lacks are computed by the given expressions or left empty.
+fn copy_table(tx: &Transaction, table: &str, computed: &[(&str, &str)]) {
+    let old_table = format!("old_{}", table);
+    let old_columns = columns(tx, &old_table);
+    let mut targets = vec![];
+    let mut values = vec![];
+    for column in columns(tx, table) {
+        let value = if old_columns.contains(&column) {
+            column.clone()
+        } else {
+            match computed.iter().find(|(c, _)| *c == column) {
+                Some((_, expression)) => expression.to_string(),
+                None => continue,
+            }
+        };
+        targets.push(column);
+        values.push(value);
+    }
+    tx.execute(
+        &*format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}",
+            table,
+            targets.join(", "),
+            values.join(", "),
+            old_table
+        ),
+        [],
+    )
+    .unwrap();
+}
+
+/// Brings a database created by an older version of pack up to the current schema. The tables of
+/// the installed packages are recreated and their rows copied by column name, as rows are copied
+/// between the tables of the installed, available and requested packages by position. Available
+/// packages have to be fetched again.
+fn migrate(tx: &Transaction) {
+    let version: i64 = tx
+        .query_row("PRAGMA user_version", [], |r| r.get(0))
+        .unwrap();
+    if version >= SCHEMA_VERSION {
+        return;
+    }
+    if table_exists(tx, "status") {
+        println!("Migrating the database to the current schema");
+        // Views and indexes would move along with the renamed tables
+        for view in &["installed", "needed_dependencies"] {
+            tx.execute(&*format!("DROP VIEW IF EXISTS {}", view), [])
+                .unwrap();
+        }
+        for index in &[
+            "dependencies_package",
+            "installed_files_file",
+            "installed_files_package",
+        ] {
+            tx.execute(&*format!("DROP INDEX IF EXISTS {}", index), [])
+                .unwrap();
+        }
+        for table in &INSTALLED_TABLES {
+            if table_exists(tx, table) {
+                tx.execute(&*format!("ALTER TABLE {0} RENAME TO old_{0}", table), [])
+                    .unwrap();
+            }
+        }
+        create_tables(tx, "");
+
+        for table in &INSTALLED_TABLES {
+            if !table_exists(tx, &format!("old_{}", table)) {
+                continue;
+            }
+            // Without groups, every relation had to be fulfilled on its own
+            let computed: &[(&str, &str)] = match *table {
+                "dependencies" => &[
+                    (
+                        "package_version",
+                        "IFNULL((SELECT version FROM old_status as s
+                            WHERE s.package = old_dependencies.package), '')",
+                    ),
+                    ("alt_group", "rowid"),
+                ],
+                _ => &[],
+            };
+            copy_table(tx, table, computed);
+        }
+        // The dpkg status used to be stored as a whole
+        if columns(tx, "old_status").iter().any(|c| c == "status") {
+            let statuses: Vec<(String, String)> = {
+                let mut stmt = tx
+                    .prepare("SELECT package, status FROM old_status WHERE status IS NOT NULL")
+                    .unwrap();
+                let statuses = stmt
+                    .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                    .unwrap()
+                    .map(|r| r.unwrap())
+                    .collect();
+                statuses
+            };
+            for (package, status) in statuses {
+                set_state(tx, "status", &package, &status);
+            }
+        }
+        // Files are looked up by their path as recorded for the data archive
+        if version < 2 {
+            tx.execute(
+                "UPDATE installed_files SET file = './' || LTRIM(file, '/')
+                WHERE file NOT LIKE './%'",
+                [],
+            )
+            .unwrap();
+        }
+        // Descriptions used to be stored without the indentation of their continuation lines
+        if version < 3 {
+            let descriptions: Vec<(String, String)> = {
+                let mut stmt = tx
+                    .prepare("SELECT package, description FROM status WHERE INSTR(description, char(10))")
+                    .unwrap();
+                let descriptions = stmt
+                    .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                    .unwrap()
+                    .map(|r| r.unwrap())
+                    .collect();
+                descriptions
+            };
+            for (package, description) in descriptions {
+                let mut lines = description.lines();
+                let mut raw = lines.next().unwrap_or("").to_string();
+                for line in lines {
+                    raw.push_str("\n ");
+                    raw.push_str(line);
+                }
+                tx.execute(
+                    "UPDATE status SET description = ?2 WHERE package = ?1",
+                    params![package, raw],
+                )
+                .unwrap();
+            }
+        }
+        for table in &INSTALLED_TABLES {
+            tx.execute(&*format!("DROP TABLE IF EXISTS old_{}", table), [])
+                .unwrap();
+        }
+        if table_exists(tx, "status_available") {
+            println!("Available packages have been dropped, run update or populate again");
+        }
+    }
+    for table in &[
+        "status_available",
+        "dependencies_available",
+        "conffiles_available",
+        "search_index",
+        "status_temp",
+        "dependencies_temp",
+        "conffiles_temp",
+    ] {
+        tx.execute(&*format!("DROP TABLE IF EXISTS {}", table), [])
+            .unwrap();
+    }
+    tx.execute(&*format!("PRAGMA user_version = {}", SCHEMA_VERSION), [])
+        .unwrap();
+}
+
 pub fn setup_db(db_name: &str, suffix: &str) {
     let mut conn = SQLite::init(db_name);
     let tx = conn.transaction().unwrap();
+    migrate(&tx);
+    create_tables(&tx, suffix);
+    tx.commit().unwrap();
+}
+
+/// Creates the tables with the given suffix and the tables shared by all of them
+fn create_tables(tx: &Transaction, suffix: &str) {
     tx.execute(
         "CREATE TABLE IF NOT EXISTS priorities (\
             id INT PRIMARY KEY,\
@@ -57,15 +260,25 @@ pub fn setup_db(db_name: &str, suffix: &str) {
     )
     .unwrap();
 
+    // Only the available packages, and the new ones staged by update, may contain several
+    // versions, architectures and origins of a package
+    let key = if suffix.starts_with("_available") {
+        "PRIMARY KEY(package, version, architecture, origin)"
+    } else {
+        "PRIMARY KEY(package)"
+    };
     tx.execute(
         &*format!(
             "CREATE TABLE IF NOT EXISTS status{} (\
-            package TEXT PRIMARY KEY, \
-            status TEXT, \
+            package TEXT NOT NULL, \
+            want TEXT, \
+            flag TEXT, \
+            state TEXT, \
             priority INT, \
             section TEXT, \
             source TEXT, \
             version TEXT NOT NULL, \
+            config_version TEXT, \
             maintainer_name TEXT, \
             maintainer_mail TEXT, \
             architecture TEXT, \
@@ -73,15 +286,24 @@ pub fn setup_db(db_name: &str, suffix: &str) {
             installed_size INT,\
             description TEXT,\
             homepage TEXT,\
+            essential TEXT,\
+            protected TEXT,\
+            important TEXT,\
             auto_installed INT,\
             filename TEXT, \
+            preinst TEXT, \
             prerm TEXT, \
+            postinst TEXT, \
             postrm TEXT, \
             md5 TEXT CHECK(LENGTH(md5) = 32), \
             sha256 TEXT CHECK(LENGTH(sha256) = 64), \
+            size INT, \
+            release TEXT, \
+            origin TEXT NOT NULL DEFAULT '', \
+            {}, \
             FOREIGN KEY(priority) REFERENCES priorities(id) \
             )",
-            suffix
+            suffix, key
         ),
         [],
     )
@@ -91,10 +313,12 @@ pub fn setup_db(db_name: &str, suffix: &str) {
         &*format!(
             "CREATE TABLE IF NOT EXISTS dependencies{0} (\
             package TEXT NOT NULL,\
+            package_version TEXT NOT NULL,\
             type TEXT NOT NULL,\
             dependency TEXT NOT NULL,\
             version_cmp TEXT,\
             version TEXT,\
+            alt_group INT NOT NULL,\
             FOREIGN KEY(package) REFERENCES status(package{0})
             )",
             suffix
@@ -103,20 +327,109 @@ pub fn setup_db(db_name: &str, suffix: &str) {
     )
     .unwrap();
 
+    if suffix.is_empty() {
+        // Removed packages may stay in the status table with their configuration files
+        tx.execute("DROP VIEW IF EXISTS installed", []).unwrap();
+        tx.execute(
+            "CREATE VIEW installed AS \
+            SELECT * FROM status \
+            WHERE IFNULL(state, '') NOT IN ('config-files', 'not-installed')",
+            [],
+        )
+        .unwrap();
+
+        // Dependencies which are only fulfilled by the given package, i.e. no other alternative
+        // of the same OR-group is installed. Virtual packages are fulfilled by their providers.
+        tx.execute("DROP VIEW IF EXISTS needed_dependencies", [])
+            .unwrap();
+        tx.execute(
+            "CREATE VIEW needed_dependencies AS \
+            WITH satisfiers AS ( \
+                SELECT d.package, d.type, d.alt_group, d.dependency as satisfier \
+                FROM dependencies as d, installed as i \
+                WHERE (d.type = 'depends' OR d.type = 'pre-depends') AND d.package = i.package \
+                UNION \
+                SELECT d.package, d.type, d.alt_group, p.package as satisfier \
+                FROM dependencies as d, dependencies as p, installed as i \
+                WHERE (d.type = 'depends' OR d.type = 'pre-depends') AND d.package = i.package AND \
+                      p.type = 'provides' AND p.dependency = d.dependency) \
+            SELECT dep.package, dep.satisfier as dependency \
+            FROM satisfiers as dep \
+            WHERE NOT EXISTS (\
+                SELECT * FROM satisfiers as alt, installed as s \
+                WHERE alt.package = dep.package AND alt.type = dep.type AND \
+                      alt.alt_group = dep.alt_group AND alt.satisfier != dep.satisfier AND \
+                      s.package = alt.satisfier)",
+            [],
+        )
+        .unwrap();
+    }
+
+    // Dependencies are looked up and replaced by package
+    tx.execute(
+        &format!(
+            "CREATE INDEX IF NOT EXISTS dependencies{0}_package ON dependencies{0}(package)",
+            suffix
+        ),
+        [],
+    )
+    .unwrap();
+
+    if suffix == "_available" {
+        // Full-text index of the available packages, rebuilt after importing them
+        tx.execute(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(package, description)",
+            [],
+        )
+        .unwrap();
+    }
+
+    if suffix.is_empty() {
+        // Write-ahead journal of the steps and file changes of a running transaction
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS journal (\
+                id INTEGER PRIMARY KEY,\
+                package TEXT NOT NULL,\
+                action TEXT NOT NULL,\
+                path TEXT,\
+                backup TEXT,\
+                previous TEXT)",
+            [],
+        )
+        .unwrap();
+    }
+
     tx.execute(
         "CREATE TABLE IF NOT EXISTS installed_files (\
             package TEXT NOT NULL,\
             file TEXT NOT NULL,\
+            md5 TEXT,\
+            mode INT,\
+            uid INT,\
+            gid INT,\
+            size INT,\
             FOREIGN KEY(package) REFERENCES status(package)
             )",
         [],
     )
     .unwrap();
+    // Files are looked up by path to find their owner and by package to remove them
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS installed_files_file ON installed_files(file)",
+        [],
+    )
+    .unwrap();
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS installed_files_package ON installed_files(package)",
+        [],
+    )
+    .unwrap();
 
     tx.execute(
         &*format!(
             "CREATE TABLE IF NOT EXISTS conffiles{0} (\
             package TEXT NOT NULL,\
+            package_version TEXT,\
             conffile TEXT NOT NULL,\
             hash TEXT,\
             FOREIGN KEY(package) REFERENCES status(package{0})
@@ -126,11 +439,37 @@ pub fn setup_db(db_name: &str, suffix: &str) {
         [],
     )
     .unwrap();
+}
+
+pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
+    populate_db_from(db_name, file, suffix, "", None);
+    if suffix == "_available" {
+        rebuild_search_index(db_name);
+    }
+}
 
+/// Rebuilds the full-text index over the names and descriptions of the available packages
+pub fn rebuild_search_index(db_name: &str) {
+    let mut conn = SQLite::init(db_name);
+    let tx = conn.transaction().unwrap();
+    tx.execute("DELETE FROM search_index", []).unwrap();
+    tx.execute(
+        "INSERT INTO search_index (package, description)
+        SELECT DISTINCT TRIM(package), IFNULL(description, '') FROM status_available",
+        [],
+    )
+    .unwrap();
     tx.commit().unwrap();
 }
 
-pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
+/// Imports the stanzas of the file, recording the repository they were retrieved from
+pub fn populate_db_from(
+    db_name: &str,
+    file: &Path,
+    suffix: &str,
+    origin: &str,
+    release: Option<&str>,
+) {
     let mut conn = SQLite::init(db_name);
     let status_file = File::open(file).unwrap();
     let mut buf_parse = BufParse::new(status_file, 4096);
@@ -139,11 +478,14 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
         .prepare(&*format!(
             "REPLACE INTO status{} (\
                     package, \
-                    status, \
+                    want, \
+                    flag, \
+                    state, \
                     priority, \
                     section, \
                     source, \
                     version, \
+                    config_version, \
                     maintainer_name, \
                     maintainer_mail, \
                     architecture, \
@@ -154,10 +496,17 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
                     auto_installed,\
                     filename,\
                     md5,\
-                    sha256) \
-                    VALUES (?1, ?2, \
-                    (SELECT id FROM priorities WHERE priority = ?3), \
-                    ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, 0, ?14, ?15, ?16)",
+                    sha256,\
+                    size,\
+                    release,\
+                    origin,\
+                    essential,\
+                    protected,\
+                    important) \
+                    VALUES (?1, ?2, ?3, ?4, \
+                    (SELECT id FROM priorities WHERE priority = ?5), \
+                    ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, 0, ?17, ?18, ?19, ?20, ?21, \
+                    ?22, ?23, ?24, ?25)",
             suffix
         ))
         .unwrap();
@@ -165,14 +514,23 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
         .prepare(&*format!(
             "INSERT INTO dependencies{} (\
                     package, \
+                    package_version, \
                     type, \
                     dependency, \
                     version_cmp,\
-                    version) \
-                    VALUES (?1, ?2, ?3, \
+                    version,\
+                    alt_group) \
+                    VALUES (?1, ?6, ?2, ?3, \
                     (SELECT substr(?4, 0, INSTR(?4, ' '))), \
-                    (SELECT trim(substr(?4, INSTR(?4, ' ')))) \
-                    )",
+                    (SELECT trim(substr(?4, INSTR(?4, ' ')))), \
+                    ?5)",
+            suffix
+        ))
+        .unwrap();
+    // Only the available packages keep the dependencies of several versions
+    let mut clear_depends_stmt = tx
+        .prepare(&format!(
+            "DELETE FROM dependencies{} WHERE package = ?1 AND (?2 IS NULL OR package_version = ?2)",
             suffix
         ))
         .unwrap();
@@ -181,11 +539,20 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
             "INSERT INTO conffiles{} (\
                     package, \
                     conffile, \
-                    hash) \
-                    VALUES (?1, ?2, ?3)",
+                    hash, \
+                    package_version) \
+                    VALUES (?1, ?2, ?3, ?4)",
+            suffix
+        ))
+        .unwrap();
+    // Only the conffiles of available packages are kept per version
+    let mut clear_conffiles_stmt = tx
+        .prepare(&format!(
+            "DELETE FROM conffiles{} WHERE package = ?1 AND package_version = ?2",
             suffix
         ))
         .unwrap();
+    let mut raw_stanzas = raw_stanzas(file);
     while let Some(entry) = buf_parse.try_next().unwrap() {
         match entry {
             Streaming::Item(paragraph) => {
@@ -193,32 +560,63 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
                 for field in paragraph.fields {
                     fields.insert(field.name.trim(), field.value);
                 }
+                // Descriptions are stored as written, the parser trims their continuation lines
+                let package = fields.get("Package").map(|p| p.as_str());
+                let description = raw_stanzas
+                    .find(|s| raw_field(s, "Package").as_deref().map(str::trim) == package)
+                    .and_then(|s| raw_field(&s, "Description"))
+                    .or_else(|| fields.get("Description").cloned());
                 let mut maintainer_iter = fields.get("Maintainer").unwrap().split('<');
                 let maintainer_name = maintainer_iter.next().unwrap();
                 let maintainer_mail = maintainer_iter
                     .next()
                     .map(|maintainer_mail_value| maintainer_mail_value.replace(">", ""));
+                let (want, flag, state) =
+                    parse_status(fields.get("Status").map(|s| s.as_str()).unwrap_or(""));
+                // dpkg omits the configured version if it is the installed one
+                let config_version = match fields.get("Config-Version") {
+                    Some(version) => Some(version),
+                    None if state == Some("installed") => fields.get("Version"),
+                    None => None,
+                };
                 status_stmt
                     .execute(params![
                         fields.get("Package"),
-                        fields.get("Status"),
+                        want,
+                        flag,
+                        state,
                         fields.get("Priority"),
                         fields.get("Section"),
                         fields.get("Source"),
                         fields.get("Version"),
+                        config_version,
                         maintainer_name,
                         maintainer_mail,
                         fields.get("Architecture"),
                         fields.get("Multi-Arch"),
                         fields.get("Installed-Size"),
-                        fields.get("Description"),
+                        description,
                         fields.get("Homepage"),
                         fields.get("Filename"),
                         fields.get("MD5sum"),
                         fields.get("SHA256"),
+                        fields.get("Size"),
+                        release,
+                        origin,
+                        fields.get("Essential"),
+                        fields.get("Protected"),
+                        fields.get("Important"),
                     ])
                     .unwrap();
 
+                let version = if suffix.starts_with("_available") {
+                    fields.get("Version")
+                } else {
+                    None
+                };
+                clear_depends_stmt
+                    .execute(params![fields.get("Package"), version])
+                    .unwrap();
                 for dep_type in &[
                     "Depends",
                     "Pre-Depends",
@@ -229,12 +627,28 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
                     "Recommends",
                     "Enhances",
                     "Conflicts",
-                    "Build-Using",
+                    "Built-Using",
                 ] {
                     process_dep(&mut depends_stmt, &fields, dep_type);
                 }
 
-                process_conffiles(&mut conffiles_stmt, &fields)
+                clear_conffiles_stmt
+                    .execute(params![fields.get("Package"), version])
+                    .unwrap();
+                process_conffiles(&mut conffiles_stmt, &fields, version);
+
+                // dpkg keeps the files and scripts of installed packages next to its status file
+                if suffix.is_empty() {
+                    let info_dir = file.with_file_name("info");
+                    if info_dir.is_dir() {
+                        import_dpkg_info(
+                            &tx,
+                            &info_dir,
+                            fields.get("Package").unwrap(),
+                            fields.get("Architecture"),
+                        );
+                    }
+                }
             }
             Streaming::Incomplete => {
                 buf_parse.buffer().unwrap();
@@ -243,11 +657,100 @@ pub fn populate_db(db_name: &str, file: &Path, suffix: &str) {
     }
     status_stmt.finalize().unwrap();
     depends_stmt.finalize().unwrap();
+    clear_depends_stmt.finalize().unwrap();
     conffiles_stmt.finalize().unwrap();
+    clear_conffiles_stmt.finalize().unwrap();
     tx.commit().unwrap();
 }
 
-fn process_conffiles(conffiles_stmt: &mut Statement, fields: &HashMap<&str, String>) {
+/// Reads a file of the package from dpkg's info directory. The names of multi-arch packages are
+/// qualified with their architecture.
+fn read_dpkg_info(
+    info_dir: &Path,
+    package: &str,
+    architecture: Option<&String>,
+    extension: &str,
+) -> Option<String> {
+    fs::read_to_string(info_dir.join(format!("{}.{}", package, extension)))
+        .or_else(|e| match architecture {
+            Some(architecture) => fs::read_to_string(
+                info_dir.join(format!("{}:{}.{}", package, architecture, extension)),
+            ),
+            None => Err(e),
+        })
+        .ok()
+}
+
+/// Imports the file list, hashes, conffiles and maintainer scripts dpkg keeps for the package, so
+/// packages installed by dpkg can be removed by pack
+fn import_dpkg_info(
+    tx: &Transaction,
+    info_dir: &Path,
+    package: &str,
+    architecture: Option<&String>,
+) {
+    let read = |extension| read_dpkg_info(info_dir, package, architecture, extension);
+    if let Some(list) = read("list") {
+        let md5sums = parse_md5sums(&read("md5sums").unwrap_or_default());
+        let paths: Vec<&str> = list
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && *l != "/.")
+            .collect();
+        tx.execute(
+            "DELETE FROM installed_files WHERE package = ?1",
+            params![package],
+        )
+        .unwrap();
+        let mut files_stmt = tx
+            .prepare_cached("INSERT INTO installed_files (package, file, md5) VALUES (?1, ?2, ?3)")
+            .unwrap();
+        // Like for unpacked packages, only files are recorded. The list contains the parent
+        // directories as well.
+        let directories: HashSet<&Path> =
+            paths.iter().filter_map(|p| Path::new(p).parent()).collect();
+        for path in &paths {
+            if directories.contains(Path::new(path)) {
+                continue;
+            }
+            let path = normalize_path(path);
+            files_stmt
+                .execute(params![package, path, md5sums.get(&path[2..])])
+                .unwrap();
+        }
+    }
+
+    // The Conffiles field lists the conffiles with their hash already
+    if let Some(conffiles) = read("conffiles") {
+        for conffile in parse_conffiles(&conffiles) {
+            tx.execute(
+                "INSERT INTO conffiles (package, conffile) SELECT ?1, ?2
+                WHERE NOT EXISTS (SELECT * FROM conffiles WHERE package = ?1 AND conffile = ?2)",
+                params![package, conffile],
+            )
+            .unwrap();
+        }
+    }
+
+    tx.execute(
+        "UPDATE status SET preinst = ?2, prerm = ?3, postinst = ?4, postrm = ?5
+        WHERE package = ?1",
+        params![
+            package,
+            read("preinst"),
+            read("prerm"),
+            read("postinst"),
+            read("postrm")
+        ],
+    )
+    .unwrap();
+}
+
+fn process_conffiles(
+    conffiles_stmt: &mut Statement,
+    fields: &HashMap<&str, String>,
+    version: Option<&String>,
+) {
     if let Some(conffiles) = fields.get("Conffiles") {
         let package = fields.get("Package");
         for conffile in conffiles.split('\n') {
@@ -258,19 +761,74 @@ fn process_conffiles(conffiles_stmt: &mut Statement, fields: &HashMap<&str, Stri
             }
             let conffile_hash = split_iter.next().unwrap_or("").trim();
             conffiles_stmt
-                .execute(params![package, conffile_name, conffile_hash,])
+                .execute(params![package, conffile_name, conffile_hash, version])
                 .unwrap();
         }
     }
 }
 
+/// Reads the stanzas of a control file as lines, skipping comments
+fn raw_stanzas(file: &Path) -> impl Iterator<Item = Vec<String>> {
+    let mut lines = BufReader::new(File::open(file).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    iter::from_fn(move || {
+        let mut stanza = vec![];
+        for line in lines.by_ref() {
+            if line.trim().is_empty() {
+                if stanza.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            if !line.starts_with('#') {
+                stanza.push(line);
+            }
+        }
+        if stanza.is_empty() {
+            None
+        } else {
+            Some(stanza)
+        }
+    })
+}
+
+/// Returns the value of a field of a stanza read by `raw_stanzas`, keeping its continuation lines
+/// as they are
+fn raw_field(stanza: &[String], name: &str) -> Option<String> {
+    let start = stanza.iter().position(|line| {
+        !line.starts_with(' ')
+            && !line.starts_with('\t')
+            && line.split_once(':').map(|(n, _)| n.trim()) == Some(name)
+    })?;
+    let mut value = stanza[start]
+        .split_once(':')
+        .unwrap()
+        .1
+        .trim_start()
+        .to_string();
+    for line in stanza[start + 1..]
+        .iter()
+        .take_while(|l| l.starts_with(' ') || l.starts_with('\t'))
+    {
+        value.push('\n');
+        value.push_str(line);
+    }
+    Some(value)
+}
+
 fn process_dep(depends_stmt: &mut Statement, fields: &HashMap<&str, String>, dep_type: &str) {
     if let Some(depends) = fields.get(dep_type) {
         let package = fields.get("Package");
-        for dep in depends.split(", ") {
+        let version = fields.get("Version");
+        // Alternatives of an OR-group share the same alt_group
+        for (alt_group, dep) in depends.split(',').enumerate() {
             for alternatives in dep.split('|') {
                 let mut split_iter = alternatives.split('(');
                 let dep_name = split_iter.next().unwrap().trim();
+                if dep_name.is_empty() {
+                    continue;
+                }
                 let dep_version = split_iter
                     .next()
                     .map(|dep_version_value| dep_version_value.replace(")", ""));
@@ -279,7 +837,9 @@ fn process_dep(depends_stmt: &mut Statement, fields: &HashMap<&str, String>, dep
                         package,
                         dep_type.to_lowercase(),
                         dep_name,
-                        dep_version
+                        dep_version,
+                        alt_group as i64,
+                        version
                     ])
                     .unwrap();
             }
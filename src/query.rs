@@ -0,0 +1,349 @@
+use crate::db_backend::SQLite;
+use crate::export::stanza;
+use crate::populate::table_exists;
+use crate::resolve::{get_candidate, get_installed_version, Relation};
+use crate::upgrade::get_upgradable;
+use crate::utils::native_architecture;
+use deb_version::compare_versions;
+use regex::RegexBuilder;
+use rusqlite::{params, Connection};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+
+/// Packages to list
+#[derive(Clone, Copy)]
+pub enum ListFilter {
+    All,
+    Installed,
+    Upgradable,
+    Auto,
+    Manual,
+}
+
+/// Returns the candidate version of the package, i.e. the highest available one
+fn candidate(conn: &Connection, package: &str) -> Option<String> {
+    let relation = Relation {
+        package: "command line".to_string(),
+        dep_type: "depends".to_string(),
+        dependency: package.to_string(),
+        version_cmp: None,
+        version: None,
+    };
+    get_candidate(conn, &relation, None)
+}
+
+/// Returns (package, version, short description) for the highest version of every known package,
+/// installed or available for the native architecture
+fn known_packages(conn: &Connection) -> Vec<(String, String, String)> {
+    let mut stmt = conn
+        .prepare("SELECT TRIM(package), version, IFNULL(description, '') FROM installed")
+        .unwrap();
+    let mut rows: Vec<(String, String, String)> = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    // Before update or populate -a, there are no available packages
+    if table_exists(conn, "status_available") {
+        let mut stmt = conn
+            .prepare(
+                "SELECT TRIM(package), version, IFNULL(description, '') FROM status_available
+                WHERE architecture = 'all' OR architecture = ?1",
+            )
+            .unwrap();
+        rows.extend(
+            stmt.query_map(params![native_architecture()], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .unwrap()
+            .map(|r| r.unwrap()),
+        );
+    }
+    rows.sort();
+    let mut packages: Vec<(String, String, String)> = vec![];
+    for (package, version, description) in rows {
+        match packages.last_mut() {
+            Some(last) if last.0 == package => {
+                if compare_versions(&version, &last.1) == Ordering::Greater {
+                    *last = (package, version, description);
+                }
+            }
+            _ => packages.push((package, version, description)),
+        }
+    }
+    packages
+}
+
+/// Returns the candidate versions of all packages available for the native architecture
+fn candidates(conn: &Connection) -> HashMap<String, String> {
+    if !table_exists(conn, "status_available") {
+        return HashMap::new();
+    }
+    let mut stmt = conn
+        .prepare(
+            "SELECT TRIM(package), version FROM status_available
+            WHERE architecture = 'all' OR architecture = ?1",
+        )
+        .unwrap();
+    let mut candidates: HashMap<String, String> = HashMap::new();
+    let rows = stmt
+        .query_map(params![native_architecture()], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })
+        .unwrap()
+        .map(|r| r.unwrap());
+    for (package, version) in rows {
+        match candidates.get(&package) {
+            Some(other) if compare_versions(other, &version) != Ordering::Less => {}
+            _ => {
+                candidates.insert(package, version);
+            }
+        }
+    }
+    candidates
+}
+
+/// Returns the versions of the installed packages
+fn installed_versions(conn: &Connection) -> HashMap<String, String> {
+    let mut stmt = conn
+        .prepare("SELECT TRIM(package), version FROM installed")
+        .unwrap();
+    let installed = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    installed
+}
+
+/// Marks installed packages, with the installed version if it isn't the listed one
+fn marker(installed: Option<&String>, version: &str) -> String {
+    match installed {
+        Some(installed) if installed == version => " [installed]".to_string(),
+        Some(installed) => format!(" [installed: {}]", installed),
+        None => String::new(),
+    }
+}
+
+/// Prints the stanza of the installed version of the package and of its candidate, if it differs
+pub fn show(db_name: &str, package: &str) {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    let installed = get_installed_version(conn, package);
+    let candidate = candidate(conn, package);
+    if installed.is_none() && candidate.is_none() {
+        println!("Package {} not found", package);
+        return;
+    }
+    let mut stanzas = vec![];
+    if let Some(version) = &installed {
+        stanzas.extend(stanza(conn, "", package, version));
+    }
+    if let Some(version) = candidate.filter(|c| Some(c) != installed.as_ref()) {
+        stanzas.extend(stanza(conn, "_available", package, &version));
+    }
+    println!("{}", stanzas.join("\n\n"));
+}
+
+/// Lists the known packages, or the installed ones, optionally only automatically or manually
+/// installed ones, or those with a newer available version
+pub fn list(db_name: &str, filter: ListFilter) {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    match filter {
+        ListFilter::All => {
+            let installed = installed_versions(conn);
+            for (package, version, _) in known_packages(conn) {
+                println!(
+                    "{} {}{}",
+                    package,
+                    version,
+                    marker(installed.get(&package), &version)
+                );
+            }
+        }
+        ListFilter::Upgradable => {
+            for (package, old, new) in get_upgradable(conn) {
+                println!("{} {} -> {}", package, old, new);
+            }
+        }
+        _ => {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT package, version, IFNULL(architecture, ''), IFNULL(auto_installed, 0)
+                    FROM installed ORDER BY package",
+                )
+                .unwrap();
+            let rows = stmt
+                .query_map([], |r| {
+                    Ok((
+                        r.get::<_, String>(0)?,
+                        r.get::<_, String>(1)?,
+                        r.get::<_, String>(2)?,
+                        r.get::<_, bool>(3)?,
+                    ))
+                })
+                .unwrap()
+                .map(|r| r.unwrap());
+            for (package, version, architecture, auto_installed) in rows {
+                match filter {
+                    ListFilter::Auto if !auto_installed => continue,
+                    ListFilter::Manual if auto_installed => continue,
+                    _ => {}
+                }
+                let marker = if auto_installed { " [automatic]" } else { "" };
+                println!("{} {} {}{}", package, version, architecture, marker);
+            }
+        }
+    }
+}
+
+/// Prints the packages whose name or description matches the regular expression, ignoring case
+pub fn search(db_name: &str, pattern: &str) {
+    let regex = match RegexBuilder::new(pattern).case_insensitive(true).build() {
+        Ok(regex) => regex,
+        Err(e) => {
+            println!("Invalid regular expression: {}", e);
+            return;
+        }
+    };
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    let installed = installed_versions(conn);
+    for (package, version, description) in known_packages(conn) {
+        if !regex.is_match(&package) && !regex.is_match(&description) {
+            continue;
+        }
+        println!(
+            "{} {}{}",
+            package,
+            version,
+            marker(installed.get(&package), &version)
+        );
+        println!("  {}", description.lines().next().unwrap_or(""));
+    }
+}
+
+/// Searches the full-text index of the available packages, best matches first. The query may
+/// contain phrases like `"web server"` and prefixes like `compress*`. Matches are highlighted in
+/// a snippet of the description.
+pub fn search_index(db_name: &str, query: &str) {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    if !table_exists(conn, "search_index") {
+        return;
+    }
+    let (start, end) = if io::stdout().is_terminal() {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("*", "*")
+    };
+    // Matches in the name weigh more than in the description
+    let mut stmt = conn
+        .prepare(
+            "SELECT package, snippet(search_index, 1, ?2, ?3, '...', 12) FROM search_index
+            WHERE search_index MATCH ?1 ORDER BY bm25(search_index, 10.0, 1.0)",
+        )
+        .unwrap();
+    let rows = stmt.query_map(params![query, start, end], |r| {
+        Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+    });
+    let rows: Result<Vec<(String, String)>, _> = rows.and_then(|rows| rows.collect());
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("Invalid search query: {}", e);
+            return;
+        }
+    };
+    // Versions are looked up once, the index may hold several descriptions of a package
+    let mut candidates = candidates(conn);
+    let installed = installed_versions(conn);
+    for (package, snippet) in rows {
+        let version = match candidates.remove(&package) {
+            Some(version) => version,
+            None => continue,
+        };
+        println!(
+            "{} {}{}",
+            package,
+            version,
+            marker(installed.get(&package), &version)
+        );
+        println!(
+            "  {}",
+            snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+    }
+}
+
+/// Prints the installed and candidate version of the package and the origins every available
+/// version can be installed from, like `apt-cache policy`
+pub fn policy(db_name: &str, package: &str) {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    let installed = get_installed_version(conn, package);
+    let candidate = candidate(conn, package);
+
+    let mut rows: Vec<(String, String, String, String)> = vec![];
+    if table_exists(conn, "status_available") {
+        let mut stmt = conn
+            .prepare(
+                "SELECT version, origin, IFNULL(release, ''), IFNULL(architecture, '')
+                FROM status_available
+                WHERE package = ?1 AND (architecture = 'all' OR architecture = ?2)",
+            )
+            .unwrap();
+        rows = stmt
+            .query_map(params![package, native_architecture()], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+    }
+    let mut versions: Vec<(String, Vec<String>)> = vec![];
+    for (version, origin, release, architecture) in rows {
+        // Packages imported with populate have no origin
+        let origin = if origin.is_empty() { "local" } else { &origin };
+        let source = [origin, &release, &architecture]
+            .iter()
+            .filter(|p| !p.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        match versions.iter_mut().find(|(v, _)| *v == version) {
+            Some((_, sources)) => sources.push(source),
+            None => versions.push((version, vec![source])),
+        }
+    }
+    if let Some(version) = &installed {
+        let status = "status".to_string();
+        match versions.iter_mut().find(|(v, _)| v == version) {
+            Some((_, sources)) => sources.push(status),
+            None => versions.push((version.clone(), vec![status])),
+        }
+    }
+    if versions.is_empty() {
+        println!("Package {} not found", package);
+        return;
+    }
+    versions.sort_by(|a, b| compare_versions(&b.0, &a.0));
+
+    println!("{}:", package);
+    println!("  Installed: {}", installed.as_deref().unwrap_or("(none)"));
+    println!("  Candidate: {}", candidate.as_deref().unwrap_or("(none)"));
+    println!("  Version table:");
+    for (version, sources) in versions {
+        let marker = if Some(&version) == installed.as_ref() {
+            "***"
+        } else {
+            "   "
+        };
+        println!(" {} {}", marker, version);
+        for source in sources {
+            println!("        {}", source);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use crate::sources::Source;
+use crate::utils::download;
+use chrono::{DateTime, Utc};
+use debcontrol::{BufParse, Streaming};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::{tempdir, TempDir};
+
+/// The verified content of a Release file
+pub struct Release {
+    /// SHA256 hash and size of the files listed in the release, keyed by their path
+    pub files: HashMap<String, (String, u64)>,
+}
+
+impl Release {
+    /// Checks the content of a downloaded file against its hash listed in the release
+    pub fn check(&self, path: &str, content: &[u8]) -> Result<(), String> {
+        let (expected, size) = self
+            .files
+            .get(path)
+            .ok_or_else(|| format!("{} is not listed in the Release file", path))?;
+        let mut sha256 = Sha256::new();
+        sha256.update(content);
+        let hash_str = format!("{:x}", sha256.finalize());
+        if content.len() as u64 != *size || &hash_str != expected {
+            return Err(format!("Hash sum mismatch of {}", path));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+}
+
+/// Returns trusted.gpg and the keyrings in trusted.gpg.d of the configuration directory
+pub fn trusted_keyrings(config_dir: &Path) -> Vec<PathBuf> {
+    let mut keyrings = vec![];
+    let keyring = config_dir.join("trusted.gpg");
+    if keyring.exists() {
+        keyrings.push(keyring);
+    }
+    if let Ok(entries) = fs::read_dir(config_dir.join("trusted.gpg.d")) {
+        let mut files: Vec<_> = entries
+            .map(|e| e.unwrap().path())
+            .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("gpg" | "asc")))
+            .collect();
+        files.sort();
+        keyrings.extend(files);
+    }
+    keyrings
+}
+
+/// gpgv only reads binary keyrings, armored ones are converted like apt does
+fn dearmor_keyrings(keyrings: &[PathBuf], dir: &TempDir) -> Result<Vec<PathBuf>, String> {
+    let mut converted = vec![];
+    for (i, keyring) in keyrings.iter().enumerate() {
+        if keyring.extension().and_then(|e| e.to_str()) != Some("asc") {
+            converted.push(keyring.clone());
+            continue;
+        }
+        let out = dir.path().join(format!("{}.gpg", i));
+        let status = Command::new("gpg")
+            .arg("--batch")
+            .arg("--dearmor")
+            .arg("--output")
+            .arg(&out)
+            .arg(keyring)
+            .status()
+            .map_err(|e| format!("Running gpg failed: {}", e))?;
+        if !status.success() {
+            return Err(format!("Reading keyring {} failed", keyring.display()));
+        }
+        converted.push(out);
+    }
+    Ok(converted)
+}
+
+/// Verifies the signature with gpgv. Without a detached signature, the file is expected to be
+/// clearsigned and the signed content is written to `output`.
+fn verify(
+    file: &Path,
+    signature: Option<&Path>,
+    output: &Path,
+    keyrings: &[PathBuf],
+) -> Result<(), String> {
+    if keyrings.is_empty() {
+        return Err("No trusted keyrings configured".to_string());
+    }
+    let dir = tempdir().unwrap();
+    let mut cmd = Command::new("gpgv");
+    for keyring in dearmor_keyrings(keyrings, &dir)? {
+        cmd.arg("--keyring").arg(keyring);
+    }
+    match signature {
+        Some(signature) => cmd.arg(signature).arg(file),
+        None => cmd.arg("--output").arg(output).arg(file),
+    };
+    let out = cmd
+        .output()
+        .map_err(|e| format!("Running gpgv failed: {}", e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "The signature couldn't be verified: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    if signature.is_some() {
+        fs::copy(file, output).unwrap();
+    }
+    Ok(())
+}
+
+/// Returns the signed content of a clearsigned file without checking the signature
+fn strip_signature(content: &str) -> String {
+    let mut lines = content.lines();
+    if !lines.any(|l| l.starts_with("-----BEGIN PGP SIGNED MESSAGE-----")) {
+        return content.to_string();
+    }
+    // Skip the armor headers
+    lines.by_ref().take_while(|l| !l.is_empty()).for_each(drop);
+    let mut signed = String::new();
+    for line in lines.take_while(|l| !l.starts_with("-----BEGIN PGP SIGNATURE-----")) {
+        signed.push_str(line.strip_prefix("- ").unwrap_or(line));
+        signed.push('\n');
+    }
+    signed
+}
+
+fn parse_release(content: &[u8]) -> Result<Release, String> {
+    let mut buf_parse = BufParse::new(Cursor::new(content), 4096);
+    let mut fields = HashMap::new();
+    while let Some(entry) = buf_parse.try_next().map_err(|e| e.to_string())? {
+        match entry {
+            Streaming::Item(paragraph) => {
+                for field in paragraph.fields {
+                    fields.insert(field.name.trim().to_string(), field.value);
+                }
+            }
+            Streaming::Incomplete => {
+                buf_parse.buffer().unwrap();
+            }
+        }
+    }
+
+    if let Some(valid_until) = fields.get("Valid-Until") {
+        // Release files use UTC as zone name which RFC 2822 doesn't know
+        let date = valid_until.trim().replace(" UTC", " +0000");
+        let valid_until = DateTime::parse_from_rfc2822(&date)
+            .map_err(|e| format!("Invalid Valid-Until {}: {}", valid_until, e))?;
+        if valid_until < Utc::now() {
+            return Err(format!("Release file expired on {}", valid_until));
+        }
+    }
+
+    let mut files = HashMap::new();
+    for line in fields
+        .get("SHA256")
+        .map(|v| v.as_str())
+        .unwrap_or("")
+        .lines()
+    {
+        let mut split_iter = line.split_whitespace();
+        if let (Some(hash), Some(size), Some(path)) =
+            (split_iter.next(), split_iter.next(), split_iter.next())
+        {
+            let size = size.parse().map_err(|_| format!("Invalid size {}", size))?;
+            files.insert(path.to_string(), (hash.to_string(), size));
+        }
+    }
+    Ok(Release { files })
+}
+
+/// Downloads InRelease, or Release and Release.gpg, of the source and verifies the signature.
+/// Sources marked as trusted are not verified and may come without Release file.
+pub fn fetch_release(source: &Source, keyrings: &[PathBuf]) -> Result<Option<Release>, String> {
+    let base = source.release_url();
+    let keyrings = match &source.signed_by {
+        Some(keyring) => vec![PathBuf::from(keyring)],
+        None => keyrings.to_vec(),
+    };
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("Release");
+
+    if let Ok(content) = download(&format!("{}InRelease", base)) {
+        let file = dir.path().join("InRelease");
+        fs::write(&file, content).unwrap();
+        if source.trusted {
+            fs::write(
+                &output,
+                strip_signature(&fs::read_to_string(&file).unwrap()),
+            )
+            .unwrap();
+        } else {
+            verify(&file, None, &output, &keyrings)?;
+        }
+    } else if let Ok(content) = download(&format!("{}Release", base)) {
+        fs::write(&output, content).unwrap();
+        if !source.trusted {
+            let signature = dir.path().join("Release.gpg");
+            let content = download(&format!("{}Release.gpg", base))
+                .map_err(|_| "The Release file is not signed".to_string())?;
+            fs::write(&signature, content).unwrap();
+            let file = dir.path().join("Release.unverified");
+            fs::rename(&output, &file).unwrap();
+            verify(&file, Some(&signature), &output, &keyrings)?;
+        }
+    } else if source.trusted {
+        return Ok(None);
+    } else {
+        return Err(format!("{} has no Release file", base));
+    }
+
+    parse_release(&fs::read(&output).unwrap()).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_release_reads_sha256_files() {
+        let release = parse_release(
+            b"Origin: Test\n\
+            MD5Sum:\n 0123 10 main/binary-amd64/Packages\n\
+            SHA256:\n abcd 42 main/binary-amd64/Packages\n ef01 7 main/binary-amd64/Packages.gz\n",
+        )
+        .unwrap();
+        assert_eq!(release.files.len(), 2);
+        assert_eq!(
+            release.files["main/binary-amd64/Packages"],
+            ("abcd".to_string(), 42)
+        );
+        assert!(release.contains("main/binary-amd64/Packages.gz"));
+        assert!(!release.contains("main/binary-i386/Packages"));
+    }
+
+    #[test]
+    fn parse_release_checks_valid_until() {
+        let valid = parse_release(b"Valid-Until: Fri, 01 Jan 2100 00:00:00 UTC\n");
+        assert!(valid.is_ok());
+        let expired = parse_release(b"Valid-Until: Thu, 01 Jan 2015 00:00:00 UTC\n");
+        assert!(expired.err().unwrap().starts_with("Release file expired"));
+        let invalid = parse_release(b"Valid-Until: tomorrow\n");
+        assert!(invalid.err().unwrap().starts_with("Invalid Valid-Until"));
+    }
+
+    #[test]
+    fn parse_release_rejects_invalid_sizes() {
+        let release = parse_release(b"SHA256:\n abcd big Packages\n");
+        assert_eq!(release.err().unwrap(), "Invalid size big");
+    }
+
+    #[test]
+    fn check_compares_hash_and_size() {
+        let release = parse_release(
+            b"SHA256:\n \
+            2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae 3 Packages\n",
+        )
+        .unwrap();
+        assert!(release.check("Packages", b"foo").is_ok());
+        assert_eq!(
+            release.check("Packages", b"bar").err().unwrap(),
+            "Hash sum mismatch of Packages"
+        );
+        assert!(release.check("Packages.gz", b"foo").is_err());
+    }
+
+    #[test]
+    fn strip_signature_returns_signed_content() {
+        let content = "-----BEGIN PGP SIGNED MESSAGE-----\n\
+            Hash: SHA512\n\
+            \n\
+            Origin: Test\n\
+            - -----BEGIN escaped line\n\
+            -----BEGIN PGP SIGNATURE-----\n\
+            \n\
+            iQEzBAEBCgAdFiEE\n\
+            -----END PGP SIGNATURE-----\n";
+        assert_eq!(
+            strip_signature(content),
+            "Origin: Test\n-----BEGIN escaped line\n"
+        );
+    }
+
+    #[test]
+    fn strip_signature_keeps_unsigned_content() {
+        assert_eq!(strip_signature("Origin: Test\n"), "Origin: Test\n");
+    }
+}
@@ -0,0 +1,281 @@
+use crate::db_backend::SQLite;
+use crate::journal;
+use crate::plan::Plan;
+use crate::state::set_state;
+use crate::utils::{confirm, execute_stored_script, ScriptContext};
+use rusqlite::{params, Connection};
+use std::fs;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct RemoveOptions {
+    /// Remove the package even if other packages depend on it
+    pub force: bool,
+    /// Remove the conffiles as well
+    pub purge: bool,
+    /// Only print the planned actions without changing the system
+    pub simulate: bool,
+    /// Don't ask for confirmation
+    pub assume_yes: bool,
+    /// Don't print the summary, the caller already showed the whole transaction
+    pub quiet: bool,
+}
+
+/// Returns the removal of the installed packages as plan
+pub fn removal_plan(conn: &Connection, packages: &[String]) -> Plan {
+    let mut plan = Plan::default();
+    for package in packages {
+        let (version, installed_size) = conn
+            .query_row(
+                "SELECT version, IFNULL(installed_size, 0) FROM status WHERE package = ?1",
+                params![package],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .unwrap();
+        plan.installed_size_delta -= installed_size;
+        plan.removals.push((package.clone(), version));
+    }
+    plan
+}
+
+/// Returns the conffiles of the package as absolute paths
+pub fn get_conffiles(tx: &Connection, package: &str) -> Vec<String> {
+    let mut stmt = tx
+        .prepare("SELECT conffile FROM conffiles WHERE package = ?1")
+        .unwrap();
+    let conffiles = stmt
+        .query_map(params![package], |r| r.get::<_, String>(0))
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    conffiles
+}
+
+/// Removes the package. Like dpkg, its conffiles are kept and the package stays in the
+/// `config-files` state, unless it gets purged or has no conffiles. Packages other packages
+/// depend on are only marked as automatically installed, unless `force` is set. Returns false if
+/// the package wasn't removed.
+pub fn remove(db_name: &str, root: &Path, package: &str, options: &RemoveOptions) -> bool {
+    let mut conn = SQLite::init(db_name);
+    let tx = conn.transaction().unwrap();
+    let package = package.trim();
+
+    let query_result = tx.query_row(
+        "SELECT IFNULL(state, ''), IFNULL(architecture, ''), IFNULL(prerm, ''),
+        IFNULL(postinst, '') FROM status WHERE package = ?1",
+        params![package],
+        |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+            ))
+        },
+    );
+    let (state, architecture, prerm, postinst) = match query_result {
+        Ok(info) => info,
+        Err(_) => {
+            println!("{} is not installed", package);
+            return false;
+        }
+    };
+    let ctx = ScriptContext {
+        package,
+        architecture: &architecture,
+        root,
+    };
+    let config_files_only = state == "config-files";
+    if config_files_only && !options.purge {
+        println!(
+            "{} is not installed, only its configuration files are left",
+            package
+        );
+        return false;
+    }
+
+    if !config_files_only {
+        let dep_count = tx
+            .query_row(
+                "SELECT count(*) FROM needed_dependencies WHERE dependency = ?1 AND package != ?1",
+                params![package],
+                |r| r.get::<_, u64>(0),
+            )
+            .unwrap();
+
+        // is this package is a dependency, it is marked as automatically installed
+        if dep_count != 0 && options.force {
+            println!(
+                "{} is a dependency of {} package(s). Removing anyway",
+                package, dep_count
+            );
+        } else if dep_count != 0 && options.simulate {
+            println!(
+                "{} is a dependency and would be marked as automatically installed",
+                package
+            );
+            return false;
+        } else if dep_count != 0 {
+            println!("{} is a dependency. Setting auto_installed", package);
+            tx.execute(
+                "UPDATE status SET auto_installed = 1 WHERE package = ?1",
+                params![package],
+            )
+            .unwrap();
+            tx.commit().unwrap();
+            return false;
+        }
+    }
+
+    let plan = removal_plan(&tx, &[package.to_string()]);
+    if options.simulate {
+        plan.print();
+        return true;
+    }
+    if !options.quiet {
+        plan.print_summary();
+    }
+    if !options.assume_yes && !confirm("Do you want to continue?") {
+        println!("Abort.");
+        return false;
+    }
+
+    tx.commit().unwrap();
+    let db = conn.conn.as_mut().unwrap();
+    if !config_files_only {
+        // Run pre-remove script. If it fails, the package is reconfigured like dpkg does.
+        if let Err(e) = execute_stored_script(&ctx, "prerm", &prerm, &["remove"]) {
+            println!("{}", e);
+            if execute_stored_script(&ctx, "postinst", &postinst, &["abort-remove"]).is_err() {
+                set_state(db, "status", package, "install ok half-configured");
+            }
+            return false;
+        }
+    }
+
+    // Interrupted removals are finished on the next run
+    let action = if options.purge { "purge" } else { "remove" };
+    journal::begin(db, package, action, None);
+    let removed = finish_removal(db, root, package, options.purge);
+    journal::cleanup(db, root, package);
+    removed
+}
+
+/// Removes the files of the package once its pre-removal script succeeded and purges it if
+/// requested. Returns false if a maintainer script failed.
+pub fn finish_removal(conn: &mut Connection, root: &Path, package: &str, purge: bool) -> bool {
+    let query_result = conn.query_row(
+        "SELECT IFNULL(state, ''), IFNULL(architecture, ''), IFNULL(postrm, '') FROM status
+        WHERE package = ?1",
+        params![package],
+        |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        },
+    );
+    let (state, architecture, postrm) = match query_result {
+        Ok(info) => info,
+        // Purged already
+        Err(_) => return true,
+    };
+    let ctx = ScriptContext {
+        package,
+        architecture: &architecture,
+        root,
+    };
+    let conffiles = get_conffiles(conn, package);
+    if state != "config-files" {
+        set_state(
+            conn,
+            "status",
+            package,
+            "deinstall reinstreq half-installed",
+        );
+
+        // Remove everything except the conffiles
+        let tx = conn.transaction().unwrap();
+        let mut files_stmt = tx
+            .prepare("SELECT file FROM installed_files WHERE package = ?1")
+            .unwrap();
+        let files: Vec<String> = files_stmt
+            .query_map(params![package], |r| r.get::<_, String>(0))
+            .unwrap()
+            .map(|f| f.unwrap())
+            .collect();
+        files_stmt.finalize().unwrap();
+        for f in files {
+            if conffiles.iter().any(|c| c == f.trim_start_matches('.')) {
+                continue;
+            }
+            remove_installed_file(root, &f);
+            tx.execute(
+                "DELETE FROM installed_files WHERE package = ?1 AND file = ?2",
+                params![package, f],
+            )
+            .unwrap();
+        }
+
+        // Run post-remove script. The files are gone already, so the package stays
+        // half-installed if it fails.
+        let result = execute_stored_script(&ctx, "postrm", &postrm, &["remove"]);
+        if result.is_ok() {
+            set_state(&tx, "status", package, "deinstall ok config-files");
+            tx.execute(
+                "DELETE FROM dependencies WHERE package = ?1",
+                params![package],
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+        if let Err(e) = result {
+            println!("{}", e);
+            return false;
+        }
+    }
+
+    if purge || conffiles.is_empty() {
+        let tx = conn.transaction().unwrap();
+        for conffile in &conffiles {
+            for suffix in &["", ".dpkg-old", ".dpkg-new", ".dpkg-dist"] {
+                let file = format!("{}{}", conffile, suffix);
+                if root.join(file.trim_start_matches('/')).exists() {
+                    remove_installed_file(root, &file);
+                }
+            }
+        }
+        // The package stays in the config-files state if this fails, so the purge can be retried
+        if let Err(e) = execute_stored_script(&ctx, "postrm", &postrm, &["purge"]) {
+            println!("{}", e);
+            return !purge;
+        }
+        // Remove from DB
+        for table in &["status", "dependencies", "conffiles", "installed_files"] {
+            tx.execute(
+                format!("DELETE FROM {} WHERE package = ?1", table).as_str(),
+                params![package],
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+    }
+    true
+}
+
+/// Removes a file recorded in `installed_files` below the root directory and its parent directory
+/// if it became empty
+pub fn remove_installed_file(root: &Path, file: &str) {
+    let f_path = root.join(file.trim_start_matches('.').trim_start_matches('/'));
+    let f_path = f_path.as_path();
+    let f_parent = f_path.parent().unwrap();
+    println!("Remove: {}", f_path.to_str().unwrap());
+    let _ = fs::remove_file(f_path);
+    // Delete directory if empty
+    let dir = f_parent.read_dir();
+    if f_parent != root && dir.is_ok() && dir.unwrap().next().is_none() {
+        println!("Remove dir: {}", f_parent.to_str().unwrap());
+        fs::remove_dir(f_parent).unwrap();
+    }
+}
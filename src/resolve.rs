@@ -0,0 +1,694 @@
+use crate::utils::native_architecture;
+use deb_version::compare_versions;
+use rusqlite::{params, Connection};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// A single relation between packages, e.g. `Depends: libfoo (>= 2.0)`
+#[derive(Debug, Clone)]
+pub struct Relation {
+    pub package: String,
+    pub dep_type: String,
+    pub dependency: String,
+    pub version_cmp: Option<String>,
+    pub version: Option<String>,
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.version_cmp, &self.version) {
+            (Some(cmp), Some(version)) if !cmp.is_empty() => {
+                write!(f, "{} ({} {})", self.dependency, cmp, version)
+            }
+            _ => write!(f, "{}", self.dependency),
+        }
+    }
+}
+
+/// Alternatives of a dependency, e.g. `exim4 | mail-transport-agent`
+#[derive(Debug, Clone)]
+pub struct Group(pub Vec<Relation>);
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alternatives: Vec<String> = self.0.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", alternatives.join(" | "))
+    }
+}
+
+/// A planned package clashing with another planned or installed package through `Conflicts` or
+/// `Breaks`
+#[derive(Debug, Clone)]
+pub struct Clash {
+    pub relation: Relation,
+    /// Version of the package the relation refers to
+    pub version: String,
+    /// Installed package which has to be removed to resolve the clash
+    pub installed: Option<String>,
+    /// Package providing the virtual package the relation refers to
+    pub provided_by: Option<String>,
+}
+
+impl fmt::Display for Clash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let declared_by_installed = self.installed.as_ref() == Some(&self.relation.package);
+        write!(
+            f,
+            "{}{} {} {}: {} is {}",
+            self.relation.package,
+            if declared_by_installed {
+                " (installed)"
+            } else {
+                ""
+            },
+            match self.relation.dep_type.as_str() {
+                "conflicts" => "conflicts with",
+                dep_type => dep_type,
+            },
+            self.relation,
+            match &self.provided_by {
+                Some(provider) => format!(
+                    "{} {} providing {}",
+                    provider, self.version, self.relation.dependency
+                ),
+                None => format!("{} {}", self.relation.dependency, self.version),
+            },
+            if self.installed.is_some() && !declared_by_installed {
+                "installed"
+            } else {
+                "to be installed"
+            }
+        )
+    }
+}
+
+const DEPENDS: &[&str] = &["depends", "pre-depends"];
+const CONFLICTS: &[&str] = &["conflicts", "breaks"];
+
+fn get_relations(
+    conn: &Connection,
+    table: &str,
+    package: &str,
+    version: &str,
+    types: &[&str],
+) -> Vec<Group> {
+    let types: Vec<String> = types.iter().map(|t| format!("'{}'", t)).collect();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT TRIM(package), TRIM(dependency), version_cmp, version, type, alt_group
+            FROM {}
+            WHERE type IN ({}) AND TRIM(package) = ?1 AND package_version = ?2
+            ORDER BY package, type, alt_group, rowid",
+            table,
+            types.join(", ")
+        ))
+        .unwrap();
+    let mut groups: Vec<Group> = vec![];
+    let mut last_key = None;
+    let rows = stmt
+        .query_map(params![package, version], |r| {
+            Ok((
+                Relation {
+                    package: r.get(0)?,
+                    dep_type: r.get(4)?,
+                    dependency: r.get(1)?,
+                    version_cmp: r.get(2)?,
+                    version: r.get(3)?,
+                },
+                r.get::<_, i64>(5)?,
+            ))
+        })
+        .unwrap()
+        .map(|r| r.unwrap());
+    for (relation, alt_group) in rows {
+        let key = Some((
+            relation.package.clone(),
+            relation.dep_type.clone(),
+            alt_group,
+        ));
+        if key == last_key {
+            groups.last_mut().unwrap().0.push(relation);
+        } else {
+            groups.push(Group(vec![relation]));
+            last_key = key;
+        }
+    }
+    groups
+}
+
+/// A package selected for installation
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub package: String,
+    pub version: String,
+    /// Virtual package this candidate was selected for
+    pub provides: Option<String>,
+}
+
+/// Returns the installed packages fulfilling the relation, either directly or through a
+/// matching `Provides`
+fn installed_satisfiers(conn: &Connection, relation: &Relation) -> Vec<String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT TRIM(package) FROM installed
+            WHERE TRIM(package) = ?1 AND cmpversion(version, ?2, ?3)
+            UNION
+            SELECT TRIM(s.package) FROM dependencies as d, installed as s
+            WHERE d.type = 'provides' AND TRIM(d.dependency) = ?1 AND
+                  TRIM(d.package) = TRIM(s.package) AND cmpversion(d.version, ?2, ?3)",
+        )
+        .unwrap();
+    let satisfiers = stmt
+        .query_map(
+            params![relation.dependency, relation.version_cmp, relation.version],
+            |r| r.get(0),
+        )
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    satisfiers
+}
+
+/// Checks whether an installed package fulfills the relation, either directly or through a
+/// matching `Provides`
+fn installed_satisfies(conn: &Connection, relation: &Relation) -> bool {
+    !installed_satisfiers(conn, relation).is_empty()
+}
+
+/// Returns the highest available version of the package fulfilling the relation, optionally
+/// restricted to a release. Only packages for the native architecture are considered.
+pub fn get_candidate(
+    conn: &Connection,
+    relation: &Relation,
+    release: Option<&str>,
+) -> Option<String> {
+    // Before update or populate -a, there are no available packages at all
+    let mut stmt = conn
+        .prepare(
+            "SELECT version FROM status_available
+            WHERE TRIM(package) = ?1 AND cmpversion(version, ?2, ?3) AND
+                  (?4 IS NULL OR release = ?4) AND
+                  (architecture = 'all' OR architecture = ?5)",
+        )
+        .ok()?;
+    let candidate = stmt
+        .query_map(
+            params![
+                relation.dependency,
+                relation.version_cmp,
+                relation.version,
+                release,
+                native_architecture()
+            ],
+            |r| r.get::<_, String>(0),
+        )
+        .unwrap()
+        .map(|r| r.unwrap())
+        .max_by(|a, b| compare_versions(a, b));
+    candidate
+}
+
+/// Returns the available packages providing the relation together with their priority. Of each
+/// provider only the highest matching version is returned. Unversioned `Provides` only fulfill
+/// unversioned relations.
+fn get_providers(conn: &Connection, relation: &Relation) -> Vec<(String, String, Option<i64>)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT DISTINCT TRIM(s.package), s.version, s.priority
+            FROM dependencies_available as d, status_available as s
+            WHERE d.type = 'provides' AND TRIM(d.dependency) = ?1 AND
+                  TRIM(d.package) = TRIM(s.package) AND d.package_version = s.version AND
+                  cmpversion(d.version, ?2, ?3) AND
+                  (s.architecture = 'all' OR s.architecture = ?4)",
+        )
+        .unwrap();
+    let mut providers: Vec<(String, String, Option<i64>)> = vec![];
+    let rows = stmt
+        .query_map(
+            params![
+                relation.dependency,
+                relation.version_cmp,
+                relation.version,
+                native_architecture()
+            ],
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, Option<i64>>(2)?,
+                ))
+            },
+        )
+        .unwrap()
+        .map(|r| r.unwrap());
+    for (package, version, priority) in rows {
+        match providers.iter_mut().find(|(p, _, _)| *p == package) {
+            Some(provider) => {
+                if compare_versions(&version, &provider.1) == Ordering::Greater {
+                    *provider = (package, version, priority);
+                }
+            }
+            None => providers.push((package, version, priority)),
+        }
+    }
+    providers
+}
+
+/// Selects the package to install for the relation. Real packages are preferred over virtual
+/// ones. Of several providers, an installed one is chosen first, then the one with the highest
+/// priority, then the one explicitly preferred by the user. Remaining ties are broken by name.
+fn select_candidate(
+    conn: &Connection,
+    relation: &Relation,
+    preferred: &[String],
+) -> Option<Candidate> {
+    if let Some(version) = get_candidate(conn, relation, None) {
+        return Some(Candidate {
+            package: relation.dependency.clone(),
+            version,
+            provides: None,
+        });
+    }
+    get_providers(conn, relation)
+        .into_iter()
+        .min_by_key(|(package, _, priority)| {
+            (
+                get_installed_version(conn, package).is_none(),
+                priority.unwrap_or(i64::MAX),
+                !preferred.contains(package),
+                package.clone(),
+            )
+        })
+        .map(|(package, version, _)| Candidate {
+            package,
+            version,
+            provides: Some(relation.dependency.clone()),
+        })
+}
+
+fn version_matches(conn: &Connection, version: &str, relation: &Relation) -> bool {
+    conn.query_row(
+        "SELECT cmpversion(?1, ?2, ?3)",
+        params![version, relation.version_cmp, relation.version],
+        |r| r.get::<_, bool>(0),
+    )
+    .unwrap()
+}
+
+/// Ordering constraint between two planned packages
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub package: String,
+    pub dependency: String,
+    pub pre_depends: bool,
+}
+
+/// Packages to install and the dependencies between them
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub candidates: Vec<Candidate>,
+    pub edges: Vec<Edge>,
+}
+
+/// Returns the selected package fulfilling the relation, either directly or through `Provides`
+fn selected_satisfier(
+    conn: &Connection,
+    selected: &HashMap<String, String>,
+    relation: &Relation,
+) -> Option<String> {
+    match selected.get(&relation.dependency) {
+        Some(version) if version_matches(conn, version, relation) => {
+            Some(relation.dependency.clone())
+        }
+        Some(_) => None,
+        None => get_providers(conn, relation)
+            .into_iter()
+            .find(|(package, version, _)| selected.get(package) == Some(version))
+            .map(|(package, _, _)| package),
+    }
+}
+
+/// Computes the packages which have to be installed to fulfill the dependencies of the requested
+/// packages (roots) stored in `dependencies_temp`. Every relation is checked against `status`
+/// and `status_available`.
+/// Of each OR-group only one alternative is installed, preferring already selected or
+/// installed packages over the first installable alternative. Virtual packages are resolved to
+/// one of their providers.
+pub fn resolve_dependencies(
+    conn: &Connection,
+    roots: &[(String, String)],
+    preferred: &[String],
+) -> Result<Resolution, String> {
+    let mut selected: HashMap<String, String> = roots.iter().cloned().collect();
+    let mut resolution = Resolution::default();
+    let mut queue: VecDeque<Group> = VecDeque::new();
+    for (root, version) in roots {
+        queue.extend(get_relations(
+            conn,
+            "dependencies_temp",
+            root,
+            version,
+            DEPENDS,
+        ));
+    }
+
+    while let Some(group) = queue.pop_front() {
+        let package = group.0[0].package.clone();
+        let pre_depends = group.0[0].dep_type == "pre-depends";
+        if let Some(dependency) = group
+            .0
+            .iter()
+            .find_map(|r| selected_satisfier(conn, &selected, r))
+        {
+            resolution.edges.push(Edge {
+                package,
+                dependency,
+                pre_depends,
+            });
+            continue;
+        }
+        if group.0.iter().any(|r| installed_satisfies(conn, r)) {
+            continue;
+        }
+        let candidate = group
+            .0
+            .iter()
+            .filter_map(|r| select_candidate(conn, r, preferred))
+            .find(|c| !selected.contains_key(&c.package));
+        match candidate {
+            Some(candidate) => {
+                queue.extend(get_relations(
+                    conn,
+                    "dependencies_available",
+                    &candidate.package,
+                    &candidate.version,
+                    DEPENDS,
+                ));
+                selected.insert(candidate.package.clone(), candidate.version.clone());
+                resolution.edges.push(Edge {
+                    package,
+                    dependency: candidate.package.clone(),
+                    pre_depends,
+                });
+                resolution.candidates.push(candidate);
+            }
+            None => {
+                return Err(format!(
+                    "{} depends on {}, but no installable version satisfies it",
+                    package, group
+                ))
+            }
+        }
+    }
+    Ok(resolution)
+}
+
+/// Returns the relations of the given types of a planned package. Requested packages are
+/// described by `dependencies_temp`, all others by `dependencies_available`.
+fn planned_relations(
+    conn: &Connection,
+    package: &str,
+    version: &str,
+    types: &[&str],
+) -> Vec<Group> {
+    let table = if is_requested(conn, package) {
+        "dependencies_temp"
+    } else {
+        "dependencies_available"
+    };
+    get_relations(conn, table, package, version, types)
+}
+
+/// Returns the installed packages providing the virtual package with their version and the
+/// provided version
+fn installed_providers(
+    conn: &Connection,
+    virtual_package: &str,
+) -> Vec<(String, String, Option<String>)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT DISTINCT TRIM(d.package), s.version, d.version
+            FROM dependencies as d, installed as s
+            WHERE d.type = 'provides' AND TRIM(d.dependency) = ?1 AND
+                  TRIM(d.package) = TRIM(s.package)",
+        )
+        .unwrap();
+    let providers = stmt
+        .query_map(params![virtual_package], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    providers
+}
+
+/// Checks whether the version of a `Provides` fulfills the relation. Unversioned `Provides` only
+/// fulfill unversioned relations.
+fn provided_version_matches(
+    conn: &Connection,
+    version: &Option<String>,
+    relation: &Relation,
+) -> bool {
+    conn.query_row(
+        "SELECT cmpversion(?1, ?2, ?3)",
+        params![version, relation.version_cmp, relation.version],
+        |r| r.get::<_, bool>(0),
+    )
+    .unwrap()
+}
+
+/// Checks the planned packages against each other and against the installed packages for
+/// `Conflicts` and `Breaks`, on real as well as on virtual packages. Requested packages are
+/// described by `dependencies_temp`, all others by `dependencies_available`. Installed packages
+/// which get replaced by a planned version are ignored. Packages may conflict with virtual
+/// packages they provide themselves.
+pub fn find_clashes(conn: &Connection, planned: &[(String, String)]) -> Vec<Clash> {
+    let planned_versions: HashMap<&str, &str> = planned
+        .iter()
+        .map(|(p, v)| (p.as_str(), v.as_str()))
+        .collect();
+    let planned_provides: Vec<Relation> = planned
+        .iter()
+        .flat_map(|(p, v)| planned_relations(conn, p, v, &["provides"]))
+        .flat_map(|group| group.0)
+        .collect();
+    let mut clashes = vec![];
+
+    // Conflicts declared by planned packages
+    for (package, package_version) in planned {
+        for group in planned_relations(conn, package, package_version, CONFLICTS) {
+            for relation in group.0 {
+                if &relation.dependency == package {
+                    continue;
+                }
+                if let Some(version) = planned_versions.get(relation.dependency.as_str()) {
+                    if version_matches(conn, version, &relation) {
+                        clashes.push(Clash {
+                            relation: relation.clone(),
+                            version: version.to_string(),
+                            installed: None,
+                            provided_by: None,
+                        });
+                    }
+                } else if let Some(version) = get_installed_version(conn, &relation.dependency) {
+                    if version_matches(conn, &version, &relation) {
+                        clashes.push(Clash {
+                            installed: Some(relation.dependency.clone()),
+                            relation: relation.clone(),
+                            version,
+                            provided_by: None,
+                        });
+                    }
+                }
+                for provides in &planned_provides {
+                    if provides.dependency == relation.dependency
+                        && &provides.package != package
+                        && provided_version_matches(conn, &provides.version, &relation)
+                    {
+                        clashes.push(Clash {
+                            relation: relation.clone(),
+                            version: planned_versions[provides.package.as_str()].to_string(),
+                            installed: None,
+                            provided_by: Some(provides.package.clone()),
+                        });
+                    }
+                }
+                for (provider, version, provided_version) in
+                    installed_providers(conn, &relation.dependency)
+                {
+                    if &provider == package
+                        || planned_versions.contains_key(provider.as_str())
+                        || !provided_version_matches(conn, &provided_version, &relation)
+                    {
+                        continue;
+                    }
+                    clashes.push(Clash {
+                        relation: relation.clone(),
+                        version,
+                        installed: Some(provider.clone()),
+                        provided_by: Some(provider),
+                    });
+                }
+            }
+        }
+    }
+
+    // Conflicts declared by installed packages, on planned packages or on virtual packages they
+    // provide
+    let mut stmt = conn
+        .prepare(
+            "SELECT TRIM(package), type, TRIM(dependency), version_cmp, version
+            FROM dependencies
+            WHERE (type = 'conflicts' OR type = 'breaks') AND TRIM(dependency) = ?1 AND
+                  package IN (SELECT package FROM installed)",
+        )
+        .unwrap();
+    let mut installed_relations = |dependency: &str| -> Vec<Relation> {
+        stmt.query_map(params![dependency], |r| {
+            Ok(Relation {
+                package: r.get(0)?,
+                dep_type: r.get(1)?,
+                dependency: r.get(2)?,
+                version_cmp: r.get(3)?,
+                version: r.get(4)?,
+            })
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .filter(|r| !planned_versions.contains_key(r.package.as_str()))
+        .collect()
+    };
+    for (package, version) in planned {
+        for relation in installed_relations(package) {
+            if version_matches(conn, version, &relation) {
+                clashes.push(Clash {
+                    installed: Some(relation.package.clone()),
+                    relation,
+                    version: version.to_string(),
+                    provided_by: None,
+                });
+            }
+        }
+    }
+    for provides in &planned_provides {
+        for relation in installed_relations(&provides.dependency) {
+            if provided_version_matches(conn, &provides.version, &relation) {
+                clashes.push(Clash {
+                    installed: Some(relation.package.clone()),
+                    relation,
+                    version: planned_versions[provides.package.as_str()].to_string(),
+                    provided_by: Some(provides.package.clone()),
+                });
+            }
+        }
+    }
+    clashes
+}
+
+/// Returns the installed packages whose dependencies are no longer fulfilled once the given
+/// packages are removed and the planned ones are installed, with the first unfulfilled
+/// dependency, in the order they break. Packages depending on broken packages break as well.
+/// Planned packages aren't checked, they are resolved against the new versions.
+pub fn broken_dependents(
+    conn: &Connection,
+    removals: &[String],
+    planned: &[(String, String)],
+) -> Vec<(String, Group)> {
+    let selected: HashMap<String, String> = planned.iter().cloned().collect();
+    // Installed versions which are going away
+    let mut gone: HashSet<String> = removals.iter().cloned().collect();
+    let mut queue: VecDeque<String> = removals.iter().cloned().collect();
+    for (package, _) in planned {
+        if get_installed_version(conn, package).is_some() {
+            gone.insert(package.clone());
+            queue.push_back(package.clone());
+        }
+    }
+    let mut stmt = conn
+        .prepare(
+            "SELECT DISTINCT TRIM(d.package), s.version FROM dependencies as d, installed as s
+            WHERE (d.type = 'depends' OR d.type = 'pre-depends') AND TRIM(d.dependency) = ?1 AND
+                  TRIM(d.package) = TRIM(s.package)",
+        )
+        .unwrap();
+    let mut broken = vec![];
+    while let Some(package) = queue.pop_front() {
+        let mut names = vec![package.clone()];
+        if let Some(version) = get_installed_version(conn, &package) {
+            names.extend(
+                get_relations(conn, "dependencies", &package, &version, &["provides"])
+                    .into_iter()
+                    .flat_map(|group| group.0)
+                    .map(|r| r.dependency),
+            );
+        }
+        for name in &names {
+            let dependents: Vec<(String, String)> = stmt
+                .query_map(params![name], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            for (dependent, version) in dependents {
+                if gone.contains(&dependent) || selected.contains_key(&dependent) {
+                    continue;
+                }
+                let unsatisfied =
+                    get_relations(conn, "dependencies", &dependent, &version, DEPENDS)
+                        .into_iter()
+                        .filter(|group| group.0.iter().any(|r| names.contains(&r.dependency)))
+                        .find(|group| {
+                            !group.0.iter().any(|r| {
+                                selected_satisfier(conn, &selected, r).is_some()
+                                    || installed_satisfiers(conn, r)
+                                        .iter()
+                                        .any(|s| !gone.contains(s))
+                            })
+                        });
+                if let Some(group) = unsatisfied {
+                    gone.insert(dependent.clone());
+                    queue.push_back(dependent.clone());
+                    broken.push((dependent, group));
+                }
+            }
+        }
+    }
+    broken
+}
+
+pub fn get_installed_version(conn: &Connection, package: &str) -> Option<String> {
+    conn.query_row(
+        "SELECT version FROM installed WHERE TRIM(package) = ?1",
+        params![package],
+        |r| r.get::<_, String>(0),
+    )
+    .ok()
+}
+
+fn is_requested(conn: &Connection, package: &str) -> bool {
+    conn.query_row(
+        "SELECT EXISTS (SELECT * FROM status_temp WHERE TRIM(package) = ?1)",
+        params![package],
+        |r| r.get::<_, bool>(0),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_candidate_without_available_packages() {
+        let conn = Connection::open_in_memory().unwrap();
+        let relation = Relation {
+            package: "command line".to_string(),
+            dep_type: "depends".to_string(),
+            dependency: "foo".to_string(),
+            version_cmp: None,
+            version: None,
+        };
+        assert_eq!(get_candidate(&conn, &relation, None), None);
+    }
+}
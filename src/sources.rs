@@ -0,0 +1,340 @@
+use crate::utils::native_architecture;
+use debcontrol::{BufParse, Streaming};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// A binary package repository as configured in sources.list
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub uri: String,
+    pub suite: String,
+    /// Empty for flat repositories
+    pub components: Vec<String>,
+    pub architectures: Vec<String>,
+    /// Keyring to verify the release with instead of the trusted keyrings
+    pub signed_by: Option<String>,
+    /// Skip the verification of the release
+    pub trusted: bool,
+}
+
+/// A Packages index of a source together with the base URL of the packages it lists
+pub struct Index {
+    /// URL of the directory containing the Release file
+    pub base: String,
+    /// Path of the index relative to `base`, without compression extension
+    pub path: String,
+    pub origin: String,
+    pub release: String,
+}
+
+impl Index {
+    pub fn url(&self) -> String {
+        format!("{}{}", self.base, self.path)
+    }
+}
+
+impl Source {
+    /// Flat repositories have an exact path as suite and no components
+    pub fn is_flat(&self) -> bool {
+        self.suite.ends_with('/')
+    }
+
+    fn uri(&self) -> String {
+        if self.uri.ends_with('/') {
+            self.uri.clone()
+        } else {
+            format!("{}/", self.uri)
+        }
+    }
+
+    /// URL of the directory containing the InRelease and Release files
+    pub fn release_url(&self) -> String {
+        if self.is_flat() {
+            format!("{}{}", self.uri(), self.suite.trim_start_matches("./"))
+        } else {
+            format!("{}dists/{}/", self.uri(), self.suite)
+        }
+    }
+
+    /// Returns the Packages indices of the source
+    pub fn indices(&self) -> Vec<Index> {
+        if self.is_flat() {
+            let base = self.release_url();
+            return vec![Index {
+                base: base.clone(),
+                path: "Packages".to_string(),
+                origin: base,
+                release: self.suite.trim_end_matches('/').to_string(),
+            }];
+        }
+        let mut indices = vec![];
+        for component in &self.components {
+            for arch in &self.architectures {
+                indices.push(Index {
+                    base: self.release_url(),
+                    path: format!("{}/binary-{}/Packages", component, arch),
+                    origin: self.uri(),
+                    release: self.suite.clone(),
+                });
+            }
+        }
+        indices
+    }
+}
+
+fn architectures(value: Option<&str>) -> Vec<String> {
+    match value {
+        Some(archs) => archs
+            .split([',', ' '])
+            .filter(|a| !a.is_empty())
+            .map(|a| a.to_string())
+            .collect(),
+        None => vec![native_architecture().to_string()],
+    }
+}
+
+/// Parses a one-line-style entry, e.g. `deb [arch=amd64] http://deb.debian.org/debian bullseye main`
+fn parse_line(line: &str) -> Option<Source> {
+    let line = line.split('#').next().unwrap().trim();
+    let line = line.strip_prefix("deb")?;
+    if !line.starts_with(|c: char| c.is_whitespace()) {
+        // deb-src and others
+        return None;
+    }
+    let mut line = line.trim_start();
+    let mut options = HashMap::new();
+    if let Some(rest) = line.strip_prefix('[') {
+        let end = rest.find(']')?;
+        for option in rest[..end].split_whitespace() {
+            let mut split_iter = option.splitn(2, '=');
+            let key = split_iter.next().unwrap();
+            options.insert(key, split_iter.next().unwrap_or(""));
+        }
+        line = rest[end + 1..].trim_start();
+    }
+    let mut words = line.split_whitespace();
+    let uri = words.next()?.to_string();
+    let suite = words.next()?.to_string();
+    Some(Source {
+        uri,
+        suite,
+        components: words.map(|c| c.to_string()).collect(),
+        architectures: architectures(options.get("arch").copied()),
+        signed_by: options.get("signed-by").map(|k| k.to_string()),
+        trusted: options.get("trusted") == Some(&"yes"),
+    })
+}
+
+fn read_list(file: &Path) -> Vec<Source> {
+    std::fs::read_to_string(file)
+        .unwrap()
+        .lines()
+        .filter_map(parse_line)
+        .collect()
+}
+
+/// Parses a deb822-style file. Each stanza may list several URIs and suites.
+fn read_deb822(file: &Path) -> Vec<Source> {
+    let mut buf_parse = BufParse::new(File::open(file).unwrap(), 4096);
+    let mut sources = vec![];
+    while let Some(entry) = buf_parse.try_next().unwrap() {
+        match entry {
+            Streaming::Item(paragraph) => {
+                let mut fields = HashMap::new();
+                for field in paragraph.fields {
+                    fields.insert(field.name.trim().to_lowercase(), field.value);
+                }
+                let get = |name: &str| fields.get(name).map(|v| v.as_str()).unwrap_or("");
+                if !get("types").split_whitespace().any(|t| t == "deb")
+                    || get("enabled").trim() == "no"
+                {
+                    continue;
+                }
+                let components: Vec<String> = get("components")
+                    .split_whitespace()
+                    .map(|c| c.to_string())
+                    .collect();
+                let archs = fields.get("architectures").map(|v| v.as_str());
+                for uri in get("uris").split_whitespace() {
+                    for suite in get("suites").split_whitespace() {
+                        sources.push(Source {
+                            uri: uri.to_string(),
+                            suite: suite.to_string(),
+                            components: components.clone(),
+                            architectures: architectures(archs),
+                            signed_by: fields.get("signed-by").map(|k| k.trim().to_string()),
+                            trusted: get("trusted").trim() == "yes",
+                        });
+                    }
+                }
+            }
+            Streaming::Incomplete => {
+                buf_parse.buffer().unwrap();
+            }
+        }
+    }
+    sources
+}
+
+/// Reads `sources.list`, `sources.list.d/*.list` and `sources.list.d/*.sources` of the given
+/// configuration directory, e.g. /etc/apt
+pub fn read_sources(config_dir: &Path) -> Vec<Source> {
+    let mut sources = vec![];
+    let list = config_dir.join("sources.list");
+    if list.exists() {
+        sources.extend(read_list(&list));
+    }
+    if let Ok(entries) = std::fs::read_dir(config_dir.join("sources.list.d")) {
+        let mut files: Vec<_> = entries.map(|e| e.unwrap().path()).collect();
+        files.sort();
+        for file in files {
+            match file.extension().and_then(|e| e.to_str()) {
+                Some("list") => sources.extend(read_list(&file)),
+                Some("sources") => sources.extend(read_deb822(&file)),
+                _ => {}
+            }
+        }
+    }
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_line_with_components() {
+        let source = parse_line("deb http://deb.debian.org/debian bullseye main contrib").unwrap();
+        assert_eq!(source.uri, "http://deb.debian.org/debian");
+        assert_eq!(source.suite, "bullseye");
+        assert_eq!(source.components, ["main", "contrib"]);
+        assert_eq!(source.architectures, [native_architecture()]);
+        assert_eq!(source.signed_by, None);
+        assert!(!source.trusted);
+        assert_eq!(
+            source.release_url(),
+            "http://deb.debian.org/debian/dists/bullseye/"
+        );
+    }
+
+    #[test]
+    fn parse_line_with_options() {
+        let source = parse_line(
+            "deb [arch=amd64,i386 signed-by=/usr/share/keyrings/k.gpg trusted=yes] \
+            http://example.com/ stable main # comment",
+        )
+        .unwrap();
+        assert_eq!(source.architectures, ["amd64", "i386"]);
+        assert_eq!(
+            source.signed_by.as_deref(),
+            Some("/usr/share/keyrings/k.gpg")
+        );
+        assert!(source.trusted);
+        assert_eq!(source.components, ["main"]);
+        let urls: Vec<_> = source.indices().iter().map(|i| i.url()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://example.com/dists/stable/main/binary-amd64/Packages",
+                "http://example.com/dists/stable/main/binary-i386/Packages",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_line_flat_repository() {
+        let source = parse_line("deb file:///srv/repo ./").unwrap();
+        assert!(source.is_flat());
+        assert_eq!(source.release_url(), "file:///srv/repo/");
+        let indices = source.indices();
+        assert_eq!(indices.len(), 1);
+        assert_eq!(indices[0].url(), "file:///srv/repo/Packages");
+    }
+
+    #[test]
+    fn parse_line_skips_other_entries() {
+        assert!(parse_line("deb-src http://deb.debian.org/debian bullseye main").is_none());
+        assert!(parse_line("# deb http://deb.debian.org/debian bullseye main").is_none());
+        assert!(parse_line("").is_none());
+        assert!(parse_line("deb http://deb.debian.org/debian").is_none());
+    }
+
+    #[test]
+    fn deb822_stanzas() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("debian.sources");
+        fs::write(
+            &file,
+            "Types: deb deb-src\n\
+            URIs: http://deb.debian.org/debian http://mirror.example.com/debian\n\
+            Suites: bookworm bookworm-updates\n\
+            Components: main non-free\n\
+            Architectures: arm64\n\
+            Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg\n\
+            \n\
+            Types: deb-src\n\
+            URIs: http://deb.debian.org/debian\n\
+            Suites: bookworm\n\
+            Components: main\n\
+            \n\
+            Types: deb\n\
+            URIs: http://disabled.example.com/\n\
+            Suites: bookworm\n\
+            Components: main\n\
+            Enabled: no\n",
+        )
+        .unwrap();
+        let sources = read_deb822(&file);
+        let entries: Vec<_> = sources
+            .iter()
+            .map(|s| (s.uri.as_str(), s.suite.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("http://deb.debian.org/debian", "bookworm"),
+                ("http://deb.debian.org/debian", "bookworm-updates"),
+                ("http://mirror.example.com/debian", "bookworm"),
+                ("http://mirror.example.com/debian", "bookworm-updates"),
+            ]
+        );
+        for source in &sources {
+            assert_eq!(source.components, ["main", "non-free"]);
+            assert_eq!(source.architectures, ["arm64"]);
+            assert_eq!(
+                source.signed_by.as_deref(),
+                Some("/usr/share/keyrings/debian-archive-keyring.gpg")
+            );
+            assert!(!source.trusted);
+        }
+    }
+
+    #[test]
+    fn read_sources_reads_both_styles() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sources.list.d")).unwrap();
+        fs::write(
+            dir.path().join("sources.list"),
+            "deb http://a.example.com/ stable main\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("sources.list.d/b.sources"),
+            "Types: deb\nURIs: http://b.example.com/\nSuites: ./\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("sources.list.d/c.list.save"),
+            "deb http://c.example.com/ stable main\n",
+        )
+        .unwrap();
+        let uris: Vec<_> = read_sources(dir.path())
+            .into_iter()
+            .map(|s| s.uri)
+            .collect();
+        assert_eq!(uris, ["http://a.example.com/", "http://b.example.com/"]);
+    }
+}
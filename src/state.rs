@@ -0,0 +1,51 @@
+use rusqlite::{params, Connection};
+
+/// Splits a dpkg Status field like `install ok installed` into the wanted action, the error flag
+/// and the installation state
+pub fn parse_status(status: &str) -> (Option<&str>, Option<&str>, Option<&str>) {
+    let mut words = status.split_whitespace();
+    (words.next(), words.next(), words.next())
+}
+
+/// Records the state of the package, given like a dpkg Status field, e.g. `install ok unpacked`
+pub fn set_state(conn: &Connection, table: &str, package: &str, status: &str) {
+    let (want, flag, state) = parse_status(status);
+    conn.execute(
+        &format!(
+            "UPDATE {} SET want = ?2, flag = ?3, state = ?4 WHERE package = ?1",
+            table
+        ),
+        params![package, want, flag, state],
+    )
+    .unwrap();
+}
+
+/// Returns the recorded state of the package like a dpkg Status field
+pub fn get_state(conn: &Connection, package: &str) -> Option<String> {
+    conn.query_row(
+        "SELECT IFNULL(want, 'install'), IFNULL(flag, 'ok'), IFNULL(state, 'installed')
+        FROM status WHERE package = ?1",
+        params![package],
+        |r| {
+            Ok(format!(
+                "{} {} {}",
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?
+            ))
+        },
+    )
+    .ok()
+}
+
+/// Restores the state the package had before a failed step, dropping packages which weren't
+/// recorded before
+pub fn restore_state(conn: &Connection, package: &str, previous: Option<&str>) {
+    match previous {
+        Some(status) => set_state(conn, "status", package, status),
+        None => {
+            conn.execute("DELETE FROM status WHERE package = ?1", params![package])
+                .unwrap();
+        }
+    }
+}
@@ -1,34 +1,116 @@
 use crate::db_backend::SQLite;
-use crate::populate::populate_db;
-use crate::MIRROR;
+use crate::populate::{populate_db_from, rebuild_search_index, setup_db};
+use crate::release::{fetch_release, trusted_keyrings, Release};
+use crate::sources::{read_sources, Index};
+use crate::utils::download;
 use flate2::read::GzDecoder;
-use reqwest::Url;
-use std::io::Cursor;
+use rusqlite::Transaction;
+use std::io::{Cursor, Write};
+use std::path::Path;
 use tempfile::NamedTempFile;
 
-pub fn update(db_name: &str) {
-    let filename = "dists/bullseye/main/binary-amd64/Packages.gz";
-    // Download metadata
-    let url = Url::parse(MIRROR).unwrap().join(filename).unwrap();
-    let p = reqwest::blocking::get(url).unwrap();
-    if !p.status().is_success() {
-        panic!("Download of package failed. Status: {}", p.status());
+/// Downloads the Packages index, preferring the compressed variant. Indices of a release must be
+/// listed in it with a matching hash.
+fn fetch_index(index: &Index, release: Option<&Release>) -> Result<NamedTempFile, String> {
+    let mut writer = NamedTempFile::new().unwrap();
+    let gz_path = format!("{}.gz", index.path);
+    let gz_listed = release.is_none_or(|r| r.contains(&gz_path));
+    match download(&format!("{}.gz", index.url())) {
+        Ok(content) if gz_listed => {
+            if let Some(release) = release {
+                release.check(&gz_path, &content)?;
+            }
+            let mut gz = GzDecoder::new(Cursor::new(content));
+            std::io::copy(&mut gz, &mut writer).map_err(|e| e.to_string())?;
+        }
+        _ => {
+            let content = download(&index.url())?;
+            if let Some(release) = release {
+                release.check(&index.path, &content)?;
+            }
+            writer.write_all(&content).unwrap();
+        }
+    }
+    Ok(writer)
+}
+
+/// Tables holding the available packages
+const TABLES: [&str; 3] = ["status", "dependencies", "conffiles"];
+
+fn drop_tables(tx: &Transaction, suffix: &str) {
+    for table in TABLES {
+        tx.execute(&format!("DROP TABLE IF EXISTS {}{}", table, suffix), [])
+            .unwrap();
+    }
+}
+
+pub fn update(db_name: &str, config_dir: &Path) {
+    let sources = read_sources(config_dir);
+    if sources.is_empty() {
+        println!("No sources configured in {}", config_dir.display());
+        return;
+    }
+
+    // Fetch and verify everything before touching the available packages
+    let keyrings = trusted_keyrings(config_dir);
+    let mut files = Vec::new();
+    let mut failed = false;
+    for source in &sources {
+        let release = match fetch_release(source, &keyrings) {
+            Ok(release) => release,
+            Err(e) => {
+                println!("Skipping {}: {}", source.release_url(), e);
+                failed = true;
+                continue;
+            }
+        };
+        for index in source.indices() {
+            println!("Get {}", index.url());
+            match fetch_index(&index, release.as_ref()) {
+                Ok(file) => files.push((index, file)),
+                Err(e) => {
+                    println!("Skipping {}: {}", index.url(), e);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        println!("Keeping the available packages, not all sources could be fetched");
+        return;
     }
 
+    // Stage the new packages, then swap them in at once
     let mut conn = SQLite::init(db_name);
     let tx = conn.transaction().unwrap();
-    // Drop old data
-    tx.execute("DROP TABLE IF EXISTS status_available", [])
+    drop_tables(&tx, "_available_new");
+    tx.commit().unwrap();
+    setup_db(db_name, "_available_new");
+    for (index, file) in &files {
+        populate_db_from(
+            db_name,
+            file.path(),
+            "_available_new",
+            &index.origin,
+            Some(&index.release),
+        );
+    }
+    let tx = conn.transaction().unwrap();
+    drop_tables(&tx, "_available");
+    for table in TABLES {
+        tx.execute(
+            &format!(
+                "ALTER TABLE {0}_available_new RENAME TO {0}_available",
+                table
+            ),
+            [],
+        )
         .unwrap();
-    tx.execute("DROP TABLE IF EXISTS dependencies_available", [])
+    }
+    // The index keeps its name when the table is renamed, it is recreated below
+    tx.execute("DROP INDEX dependencies_available_new_package", [])
         .unwrap();
     tx.commit().unwrap();
-
-    // Unpack data
-    let content = Cursor::new(p.bytes().unwrap());
-    let mut gz = GzDecoder::new(content);
-    let mut writer = NamedTempFile::new().unwrap();
-    std::io::copy(&mut gz, &mut writer).unwrap();
-
-    populate_db(db_name, writer.path(), "_available");
+    setup_db(db_name, "_available");
+    rebuild_search_index(db_name);
 }
@@ -0,0 +1,143 @@
+use crate::db_backend::SQLite;
+use crate::install::{add_available_to_temp, drop_temp_tables, install_packages, InstallOptions};
+use crate::populate::setup_db;
+use crate::resolve::{broken_dependents, find_clashes, resolve_dependencies};
+use crate::utils::native_architecture;
+use deb_version::compare_versions;
+use rusqlite::{params, Connection};
+use std::cmp::Ordering;
+use std::path::Path;
+
+/// Returns the installed packages with a newer available version as (package, old, new)
+pub fn get_upgradable(conn: &Connection) -> Vec<(String, String, String)> {
+    // Before update or populate -a, there are no available packages at all
+    let mut stmt = match conn.prepare(
+        "SELECT s.package, s.version, a.version
+        FROM installed as s, status_available as a
+        WHERE s.package = a.package AND (a.architecture = 'all' OR a.architecture = ?1)
+        ORDER BY s.package",
+    ) {
+        Ok(stmt) => stmt,
+        Err(_) => return vec![],
+    };
+    let rows = stmt
+        .query_map(params![native_architecture()], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        })
+        .unwrap()
+        .map(|r| r.unwrap());
+    let mut upgradable: Vec<(String, String, String)> = vec![];
+    for (package, old, new) in rows {
+        if compare_versions(&new, &old) != Ordering::Greater {
+            continue;
+        }
+        // Several versions may be available, use the highest one
+        match upgradable.last_mut() {
+            Some(last) if last.0 == package => {
+                if compare_versions(&new, &last.2) == Ordering::Greater {
+                    last.2 = new;
+                }
+            }
+            _ => upgradable.push((package, old, new)),
+        }
+    }
+    upgradable
+}
+
+/// Checks whether the upgrade of the package can be done without installing new packages,
+/// removing installed ones or breaking the dependencies of installed ones
+fn is_safe_upgrade(conn: &Connection, package: &str, version: &str, preferred: &[String]) -> bool {
+    let roots = vec![(package.to_string(), version.to_string())];
+    let resolution = match resolve_dependencies(conn, &roots, preferred) {
+        Ok(resolution) => resolution,
+        Err(_) => return false,
+    };
+    let mut planned = roots;
+    for candidate in resolution.candidates {
+        let installed = conn
+            .query_row(
+                "SELECT EXISTS (SELECT * FROM installed WHERE package = ?1)",
+                params![candidate.package],
+                |r| r.get::<_, bool>(0),
+            )
+            .unwrap();
+        if !installed {
+            return false;
+        }
+        planned.push((candidate.package, candidate.version));
+    }
+    find_clashes(conn, &planned).is_empty() && broken_dependents(conn, &[], &planned).is_empty()
+}
+
+/// Upgrades all installed packages with a newer available version. Without `full`, packages
+/// requiring new packages or the removal of installed ones are kept back.
+pub fn upgrade(db_name: &str, root: &Path, full: bool, options: &InstallOptions) {
+    let mut conn = SQLite::init(db_name);
+    let upgradable = get_upgradable(conn.conn.as_ref().unwrap());
+    if upgradable.is_empty() {
+        println!("All packages are up to date");
+        return;
+    }
+
+    // Leftovers of an aborted installation must not leak into this one
+    drop_temp_tables(&mut conn);
+    setup_db(db_name, "_temp");
+
+    let tx = conn.transaction().unwrap();
+    for (package, _, version) in &upgradable {
+        add_available_to_temp(&tx, package, version);
+    }
+    tx.commit().unwrap();
+
+    let mut kept_back = vec![];
+    if !full {
+        for (package, _, new) in &upgradable {
+            if !is_safe_upgrade(
+                conn.conn.as_ref().unwrap(),
+                package,
+                new,
+                &options.preferred,
+            ) {
+                kept_back.push(package.as_str());
+            }
+        }
+        if !kept_back.is_empty() {
+            let tx = conn.transaction().unwrap();
+            for package in &kept_back {
+                for table in &["status_temp", "dependencies_temp", "conffiles_temp"] {
+                    tx.execute(
+                        &format!("DELETE FROM {} WHERE package = ?1", table),
+                        params![package],
+                    )
+                    .unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+        if kept_back.len() == upgradable.len() {
+            println!(
+                "The following packages have been kept back: {}",
+                kept_back.join(" ")
+            );
+            drop_temp_tables(&mut conn);
+            return;
+        }
+    }
+
+    let options = InstallOptions {
+        remove_conflicts: full,
+        preferred: options.preferred.clone(),
+        allow_downgrade: false,
+        simulate: options.simulate,
+        assume_yes: options.assume_yes,
+        conffile_policy: options.conffile_policy,
+    };
+    let kept_back: Vec<String> = kept_back.iter().map(|p| p.to_string()).collect();
+    if !install_packages(db_name, root, &mut conn, &kept_back, &options) {
+        println!("Upgrade failed");
+    }
+}
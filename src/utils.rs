@@ -1,29 +1,88 @@
 use deb_version::compare_versions;
 use log::warn;
+use md5::{Digest, Md5};
+use reqwest::Url;
 use rusqlite::functions::FunctionFlags;
 use rusqlite::{Connection, Result};
 use std::cmp::Ordering;
-use std::io::Error;
+use std::fs;
+use std::io::{Error, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::Command;
+use tempfile::{tempdir, NamedTempFile, TempDir};
 
-pub fn execute_script(desc: &str, pre_install_script: &Path) -> Result<(), Error> {
-    println!("Execute {} script", desc);
-    let out = Command::new("sh")
-        .arg("-c")
-        .arg(pre_install_script.to_str().unwrap())
-        .output()?;
-    let stderr = out.stderr;
+/// The package a maintainer script belongs to and the installation it runs in
+pub struct ScriptContext<'a> {
+    pub package: &'a str,
+    pub architecture: &'a str,
+    pub root: &'a Path,
+}
+
+/// Executes a maintainer script like dpkg does, i.e. with `DPKG_MAINTSCRIPT_PACKAGE`,
+/// `DPKG_MAINTSCRIPT_NAME` and `DPKG_ROOT`, which is empty for the live system. Missing scripts
+/// succeed, a non-zero exit status is an error.
+pub fn execute_script(
+    ctx: &ScriptContext,
+    name: &str,
+    script: &Path,
+    args: &[&str],
+) -> Result<(), String> {
+    if !script.exists() {
+        return Ok(());
+    }
+    println!("Execute {} {}", name, args.join(" "));
+    let dpkg_root = if ctx.root == Path::new("/") {
+        Path::new("")
+    } else {
+        ctx.root
+    };
+    let out = Command::new(script)
+        .args(args)
+        .env("DPKG_MAINTSCRIPT_PACKAGE", ctx.package)
+        .env("DPKG_MAINTSCRIPT_PACKAGE_REFCOUNT", "1")
+        .env("DPKG_MAINTSCRIPT_ARCH", ctx.architecture)
+        .env("DPKG_MAINTSCRIPT_NAME", name)
+        .env("DPKG_ROOT", dpkg_root)
+        .output()
+        .map_err(|e| format!("Executing {} of {} failed: {}", name, ctx.package, e))?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !out.status.success() {
+        return Err(format!(
+            "{} of {} returned {}: {}",
+            name,
+            ctx.package,
+            out.status,
+            stderr.trim()
+        ));
+    }
     if !stderr.is_empty() {
-        warn!(
-            "{} script warnings: {}",
-            desc,
-            String::from_utf8(stderr).unwrap()
-        );
+        warn!("{} script warnings: {}", name, stderr);
     }
     Ok(())
 }
 
+/// Executes a maintainer script stored in the database
+pub fn execute_stored_script(
+    ctx: &ScriptContext,
+    name: &str,
+    script: &str,
+    args: &[&str],
+) -> Result<(), String> {
+    if script.is_empty() {
+        return Ok(());
+    }
+    let write_script = || -> Result<tempfile::TempPath, Error> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(script.as_bytes())?;
+        let file = file.into_temp_path();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755))?;
+        Ok(file)
+    };
+    let file = write_script().map_err(|e| e.to_string())?;
+    execute_script(ctx, name, &file, args)
+}
+
 pub fn add_version_compare(db: &Connection) -> Result<()> {
     db.create_scalar_function(
         "cmpversion",
@@ -31,14 +90,153 @@ pub fn add_version_compare(db: &Connection) -> Result<()> {
         FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
         move |ctx| {
             assert_eq!(ctx.len(), 3, "Wrong number of arguments");
-            let l = ctx.get_raw(0).as_str().unwrap();
-            let cmp = ctx.get_raw(1).as_str().unwrap();
+            // Unversioned relations are satisfied by any version
+            let cmp = match ctx.get_raw(1).as_str() {
+                Ok(cmp) if !cmp.is_empty() => cmp,
+                _ => return Ok(true),
+            };
+            // Unversioned packages, e.g. unversioned Provides, never satisfy versioned relations
+            let l = match ctx.get_raw(0).as_str() {
+                Ok(l) => l,
+                Err(_) => return Ok(false),
+            };
             let r = ctx.get_raw(2).as_str().unwrap();
-            match compare_versions(l, r) {
-                Ordering::Less => Ok(cmp.starts_with('<')),
-                Ordering::Equal => Ok(cmp.ends_with('=')),
-                Ordering::Greater => Ok(cmp.starts_with('>')),
-            }
+            Ok(version_satisfies(l, cmp, r))
         },
     )
 }
+
+/// Checks whether `version` fulfills the relation `cmp required`, e.g. `>= 2.0`
+pub fn version_satisfies(version: &str, cmp: &str, required: &str) -> bool {
+    match compare_versions(version, required) {
+        Ordering::Less => cmp.starts_with('<'),
+        // `<` and `>` are deprecated aliases of `<=` and `>=`
+        Ordering::Equal => cmp.ends_with('=') || cmp == "<" || cmp == ">",
+        Ordering::Greater => cmp.starts_with('>'),
+    }
+}
+
+/// Debian name of the architecture pack runs on
+pub fn native_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        "aarch64" => "arm64",
+        "arm" => "armhf",
+        "powerpc64" => "ppc64el",
+        "mips64" => "mips64el",
+        arch => arch,
+    }
+}
+
+/// Downloads the file at the URL. Local repositories are supported via file:// URLs.
+pub fn download(url: &str) -> Result<Vec<u8>, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| format!("Invalid file URL {}", url))?;
+        return fs::read(&path).map_err(|e| format!("Reading {} failed: {}", path.display(), e));
+    }
+    let response = reqwest::blocking::get(url.clone()).map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Download of {} failed. Status: {}",
+            url,
+            response.status()
+        ));
+    }
+    response
+        .bytes()
+        .map(|b| b.to_vec())
+        .map_err(|e| e.to_string())
+}
+
+/// Simulations work on a copy of the database, so that neither the temporary tables nor any
+/// other change reach the real one. The copy lives as long as the returned directory.
+pub fn simulation_db(db_name: &str, simulate: bool) -> (Option<TempDir>, String) {
+    if !simulate {
+        return (None, db_name.to_string());
+    }
+    let dir = tempdir().unwrap();
+    let copy = dir.path().join("packages.db");
+    if Path::new(db_name).exists() {
+        fs::copy(db_name, &copy).unwrap();
+    }
+    let copy = copy.to_str().unwrap().to_string();
+    (Some(dir), copy)
+}
+
+/// Asks the user whether to continue. Without a terminal on stdin, the answer is yes.
+pub fn confirm(question: &str) -> bool {
+    ask(question, true)
+}
+
+/// Asks a yes/no question. Without a terminal on stdin or an empty answer, the default is taken.
+pub fn ask(question: &str, default: bool) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return default;
+    }
+    print!("{} {} ", question, if default { "[Y/n]" } else { "[y/N]" });
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        answer => answer == "y" || answer == "yes",
+    }
+}
+
+/// Returns the MD5 hash of the file as hex string, as used by dpkg
+pub fn md5_file(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    let mut md5 = Md5::new();
+    md5.update(&content);
+    Some(format!("{:x}", md5.finalize()))
+}
+
+/// Returns the path of a packaged file the way it is recorded in `installed_files`, relative to
+/// the root like in the data archive, e.g. `./usr/bin/foo`
+pub fn normalize_path(path: &str) -> String {
+    let mut path = path;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    format!("./{}", path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_satisfies_operators() {
+        assert!(version_satisfies("2.0", ">=", "2.0"));
+        assert!(version_satisfies("2.1", ">=", "2.0"));
+        assert!(!version_satisfies("1.9", ">=", "2.0"));
+        assert!(version_satisfies("1.9", "<=", "2.0"));
+        assert!(!version_satisfies("2.1", "<=", "2.0"));
+        assert!(version_satisfies("2.0", "=", "2.0"));
+        assert!(!version_satisfies("2.0-1", "=", "2.0"));
+        assert!(version_satisfies("1.9", "<<", "2.0"));
+        assert!(!version_satisfies("2.0", "<<", "2.0"));
+        assert!(version_satisfies("2.1", ">>", "2.0"));
+        assert!(!version_satisfies("2.0", ">>", "2.0"));
+    }
+
+    #[test]
+    fn version_satisfies_deprecated_operators() {
+        assert!(version_satisfies("2.0", "<", "2.0"));
+        assert!(version_satisfies("1.0", "<", "2.0"));
+        assert!(version_satisfies("2.0", ">", "2.0"));
+        assert!(!version_satisfies("1.0", ">", "2.0"));
+    }
+
+    #[test]
+    fn version_satisfies_debian_ordering() {
+        assert!(version_satisfies("1:1.0", ">>", "2.0"));
+        assert!(version_satisfies("2.0~rc1", "<<", "2.0"));
+        assert!(version_satisfies("2.0+b1", ">>", "2.0"));
+        assert!(version_satisfies("2.0-10", ">>", "2.0-9"));
+    }
+}
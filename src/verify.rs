@@ -0,0 +1,217 @@
+use crate::db_backend::SQLite;
+use crate::utils::md5_file;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Attributes of an installed file, recorded to detect changes later. Only regular files have
+/// attributes, conffiles only their hash.
+#[derive(Default)]
+pub struct FileAttributes {
+    pub md5: Option<String>,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<i64>,
+}
+
+impl FileAttributes {
+    /// Reads the attributes of the installed file. The hash is taken from the `md5sums` of the
+    /// package if listed there.
+    pub fn read(path: &Path, md5: Option<&String>) -> FileAttributes {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => FileAttributes {
+                md5: md5.cloned().or_else(|| md5_file(path)),
+                mode: Some(metadata.mode()),
+                uid: Some(metadata.uid()),
+                gid: Some(metadata.gid()),
+                size: Some(metadata.len() as i64),
+            },
+            _ => FileAttributes::default(),
+        }
+    }
+}
+
+/// Parses an `md5sums` file, mapping the paths without leading `/` to their hashes
+pub fn parse_md5sums(md5sums: &str) -> HashMap<String, String> {
+    md5sums
+        .lines()
+        .filter_map(|line| {
+            let (md5, path) = line.trim().split_once(' ')?;
+            Some((
+                path.trim().trim_start_matches('/').to_string(),
+                md5.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns the recorded files of the package with their attributes, keyed by their absolute path
+fn recorded_files(conn: &Connection, package: &str) -> Vec<(String, FileAttributes)> {
+    let mut stmt = conn
+        .prepare("SELECT file, md5, mode, uid, gid, size FROM installed_files WHERE package = ?1")
+        .unwrap();
+    let files = stmt
+        .query_map(params![package], |r| {
+            Ok((
+                r.get::<_, String>(0)?.trim_start_matches('.').to_string(),
+                FileAttributes {
+                    md5: r.get(1)?,
+                    mode: r.get(2)?,
+                    uid: r.get(3)?,
+                    gid: r.get(4)?,
+                    size: r.get(5)?,
+                },
+            ))
+        })
+        .unwrap()
+        .map(|f| f.unwrap())
+        .collect();
+    files
+}
+
+/// Reads the hashes of a package installed by dpkg from its `md5sums` in the dpkg database
+fn dpkg_md5sums(root: &Path, package: &str, architecture: &str) -> Vec<(String, FileAttributes)> {
+    let info_dir = root.join("var/lib/dpkg/info");
+    let md5sums = fs::read_to_string(info_dir.join(format!("{}.md5sums", package)))
+        .or_else(|_| {
+            fs::read_to_string(info_dir.join(format!("{}:{}.md5sums", package, architecture)))
+        })
+        .unwrap_or_default();
+    parse_md5sums(&md5sums)
+        .into_iter()
+        .map(|(path, md5)| {
+            let attributes = FileAttributes {
+                md5: Some(md5),
+                ..Default::default()
+            };
+            (format!("/{}", path), attributes)
+        })
+        .collect()
+}
+
+/// Compares the file with its recorded attributes. Returns the result like `dpkg --verify`,
+/// a check that couldn't be done is marked with `?`.
+fn check_file(path: &Path, attributes: &FileAttributes) -> String {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return "missing  ".to_string(),
+    };
+    let check = |recorded: Option<u32>, actual: u32, flag: char| match recorded {
+        Some(recorded) if recorded != actual => flag,
+        Some(_) => '.',
+        None => '?',
+    };
+    let size = match attributes.size {
+        Some(size) if size != metadata.len() as i64 => 'S',
+        Some(_) => '.',
+        None => '?',
+    };
+    let md5 = match (&attributes.md5, md5_file(path)) {
+        (Some(recorded), Some(actual)) if *recorded != actual => '5',
+        (Some(_), Some(_)) => '.',
+        _ => '?',
+    };
+    [
+        size,
+        check(attributes.mode, metadata.mode(), 'M'),
+        md5,
+        '?',
+        '?',
+        check(attributes.uid, metadata.uid(), 'U'),
+        check(attributes.gid, metadata.gid(), 'G'),
+        '?',
+        '?',
+    ]
+    .iter()
+    .collect()
+}
+
+/// Checks the files of the given or all installed packages and reports modified, missing or
+/// permission-changed files in the format of `dpkg --verify`. Conffiles are marked with `c`.
+/// Returns whether all files are unchanged.
+pub fn verify(db_name: &str, root: &Path, packages: &[String]) -> bool {
+    let db = SQLite::init(db_name);
+    let conn = db.conn.as_ref().unwrap();
+    let packages: Vec<(String, String)> = {
+        let mut stmt = conn
+            .prepare("SELECT package, IFNULL(architecture, '') FROM installed ORDER BY package")
+            .unwrap();
+        let installed = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(|p| p.unwrap());
+        if packages.is_empty() {
+            installed.collect()
+        } else {
+            let installed: HashMap<String, String> = installed.collect();
+            packages
+                .iter()
+                .filter_map(|package| match installed.get(package) {
+                    Some(architecture) => Some((package.clone(), architecture.clone())),
+                    None => {
+                        println!("Package {} is not installed", package);
+                        None
+                    }
+                })
+                .collect()
+        }
+    };
+
+    let mut unchanged = true;
+    for (package, architecture) in packages {
+        let mut files = recorded_files(conn, &package);
+        // Packages imported from dpkg have no recorded files
+        if files.is_empty() {
+            files = dpkg_md5sums(root, &package, &architecture);
+        }
+        let conffiles: HashMap<String, Option<String>> = {
+            let mut stmt = conn
+                .prepare("SELECT conffile, hash FROM conffiles WHERE package = ?1")
+                .unwrap();
+            let conffiles = stmt
+                .query_map(params![package], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(|c| c.unwrap())
+                .collect();
+            conffiles
+        };
+        // The hash of a conffile is the one of its configured version
+        for (conffile, hash) in &conffiles {
+            let hash = hash.clone().filter(|h| h.len() == 32);
+            match files.iter_mut().find(|(path, _)| path == conffile) {
+                Some((_, attributes)) => {
+                    *attributes = FileAttributes {
+                        md5: hash.or_else(|| attributes.md5.take()),
+                        ..Default::default()
+                    }
+                }
+                None => files.push((
+                    conffile.clone(),
+                    FileAttributes {
+                        md5: hash,
+                        ..Default::default()
+                    },
+                )),
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (path, attributes) in files {
+            let result = check_file(&root.join(path.trim_start_matches('/')), &attributes);
+            if result.chars().all(|c| c == '.' || c == '?') {
+                continue;
+            }
+            unchanged = false;
+            let attribute = if conffiles.contains_key(&path) {
+                'c'
+            } else {
+                ' '
+            };
+            println!("{} {} {}", result, attribute, path);
+        }
+    }
+    unchanged
+}
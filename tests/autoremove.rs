@@ -0,0 +1,79 @@
+//! Autoremove of packages imported from a dpkg status file
+
+use std::fs;
+use std::process::Command;
+use tempfile::{tempdir, TempDir};
+
+fn pack(root: &TempDir, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_pack"))
+        .arg("--root")
+        .arg(root.path().join("root"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stanza(package: &str, relations: &str) -> String {
+    format!(
+        "Package: {}\nStatus: install ok installed\nVersion: 1.0\nArchitecture: all\n\
+        Maintainer: Test <test@example.com>\n{}Description: {}\n\n",
+        package, relations, package
+    )
+}
+
+fn installed(root: &TempDir) -> Vec<String> {
+    pack(root, &["list"])
+        .lines()
+        .map(|l| l.split_whitespace().next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn summary_lists_the_removed_packages() {
+    let dir = tempdir().unwrap();
+    let status = [
+        stanza("app", "Depends: libfoo\n"),
+        stanza("alt", "Depends: libfoo\n"),
+        stanza("libfoo", ""),
+        stanza("unused", "Depends: libbar | libbaz, mta\n"),
+        stanza("libbar", ""),
+        stanza("mailer", "Provides: mta\nDepends: mta\n"),
+    ]
+    .concat();
+    fs::write(dir.path().join("status"), status).unwrap();
+    let auto_installed: String = ["alt", "libfoo", "unused", "libbar", "mailer"]
+        .iter()
+        .map(|p| format!("Package: {}\nAuto-Installed: 1\n\n", p))
+        .collect();
+    fs::write(dir.path().join("extended_states"), auto_installed).unwrap();
+    pack(
+        &dir,
+        &["populate", dir.path().join("status").to_str().unwrap()],
+    );
+    pack(
+        &dir,
+        &[
+            "populate",
+            "-i",
+            dir.path().join("extended_states").to_str().unwrap(),
+        ],
+    );
+
+    let before = installed(&dir);
+    let output = pack(&dir, &["autoremove", "-y"]);
+    let mut summary: Vec<String> = output
+        .lines()
+        .skip_while(|l| !l.starts_with("The following packages will be REMOVED:"))
+        .nth(1)
+        .unwrap()
+        .split_whitespace()
+        .map(|p| p.to_string())
+        .collect();
+    summary.sort();
+    let after = installed(&dir);
+    let removed: Vec<String> = before.into_iter().filter(|p| !after.contains(p)).collect();
+    assert_eq!(summary, removed);
+    assert_eq!(after, ["app", "libfoo"]);
+}
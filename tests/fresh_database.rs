@@ -0,0 +1,56 @@
+//! Commands run before any package was imported with update or populate
+
+use rusqlite::Connection;
+use std::process::Command;
+use tempfile::{tempdir, TempDir};
+
+/// Runs pack in the root and returns its stdout, failing on a crash
+fn pack(root: &TempDir, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_pack"))
+        .arg("--root")
+        .arg(root.path())
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn upgrade() {
+    let root = tempdir().unwrap();
+    assert!(pack(&root, &["upgrade"]).contains("All packages are up to date"));
+    assert!(pack(&root, &["full-upgrade"]).contains("All packages are up to date"));
+}
+
+#[test]
+fn install() {
+    let root = tempdir().unwrap();
+    assert!(pack(&root, &["install", "-s", "foo"]).contains("No installable version of foo found"));
+    assert!(pack(&root, &["install", "foo"]).contains("No installable version of foo found"));
+}
+
+#[test]
+fn queries_leave_the_available_packages_alone() {
+    let root = tempdir().unwrap();
+    assert_eq!(pack(&root, &["list"]), "");
+    assert_eq!(pack(&root, &["list", "--upgradable"]), "");
+    assert_eq!(pack(&root, &["search", "foo"]), "");
+    assert_eq!(pack(&root, &["search", "--full-text", "foo"]), "");
+    assert!(pack(&root, &["show", "foo"]).contains("Package foo not found"));
+    assert!(pack(&root, &["policy", "foo"]).contains("Package foo not found"));
+
+    let db = Connection::open(root.path().join("var/lib/pack/packages.db")).unwrap();
+    let available: i64 = db
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE name LIKE '%available%'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(available, 0);
+}
@@ -0,0 +1,194 @@
+//! Updates from a file:// repository signed with a throwaway gpg key
+
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::{tempdir, TempDir};
+
+const PACKAGES: &str = "Package: foo\n\
+    Version: 1.0\n\
+    Architecture: all\n\
+    Maintainer: Test <test@example.com>\n\
+    Filename: ./foo_1.0_all.deb\n\
+    Description: signed test package\n";
+
+/// A gpg home directory with a new key, its agent is stopped when dropped
+struct Key {
+    home: TempDir,
+}
+
+impl Key {
+    fn generate(name: &str) -> Key {
+        let home = tempdir().unwrap();
+        let key = Key { home };
+        assert!(key
+            .gpg()
+            .args(["--passphrase", "", "--quick-gen-key"])
+            .arg(format!("{} <{}@example.com>", name, name))
+            .args(["default", "default", "never"])
+            .output()
+            .unwrap()
+            .status
+            .success());
+        key
+    }
+
+    fn gpg(&self) -> Command {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--homedir")
+            .arg(self.home.path())
+            .args(["--batch", "--no-permission-warning"]);
+        cmd
+    }
+
+    fn export(&self, file: &Path) {
+        let status = self
+            .gpg()
+            .arg("--armor")
+            .arg("--output")
+            .arg(file)
+            .arg("--export")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn clearsign(&self, file: &Path, output: &Path) {
+        let status = self
+            .gpg()
+            .arg("--yes")
+            .arg("--output")
+            .arg(output)
+            .arg("--clearsign")
+            .arg(file)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(self.home.path())
+            .args(["--kill", "gpg-agent"])
+            .status()
+            .ok();
+    }
+}
+
+/// A flat repository and a root directory trusting `key`
+struct Setup {
+    dir: TempDir,
+}
+
+impl Setup {
+    fn new(key: &Key) -> Setup {
+        let dir = tempdir().unwrap();
+        let apt = dir.path().join("root/etc/apt");
+        fs::create_dir_all(apt.join("trusted.gpg.d")).unwrap();
+        fs::create_dir_all(dir.path().join("repo")).unwrap();
+        key.export(&apt.join("trusted.gpg.d/test.asc"));
+        fs::write(
+            apt.join("sources.list"),
+            format!("deb file://{}/ ./\n", dir.path().join("repo").display()),
+        )
+        .unwrap();
+        Setup { dir }
+    }
+
+    fn repo(&self) -> PathBuf {
+        self.dir.path().join("repo")
+    }
+
+    /// Writes the Packages index and an InRelease file signed by `key`
+    fn publish(&self, key: &Key, packages: &str, valid_until: &str) {
+        fs::write(self.repo().join("Packages"), packages).unwrap();
+        let release = format!(
+            "Origin: Test\nValid-Until: {}\nSHA256:\n {:x} {} Packages\n",
+            valid_until,
+            Sha256::digest(packages.as_bytes()),
+            packages.len()
+        );
+        fs::write(self.repo().join("Release"), release).unwrap();
+        key.clearsign(&self.repo().join("Release"), &self.repo().join("InRelease"));
+        fs::remove_file(self.repo().join("Release")).unwrap();
+    }
+
+    fn pack(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_pack"))
+            .arg("--root")
+            .arg(self.dir.path().join("root"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs update and returns its output and whether foo became available
+    fn update(&self) -> (String, bool) {
+        let update = self.pack(&["update"]);
+        let search = self.pack(&["search", "foo"]);
+        (
+            String::from_utf8_lossy(&update.stdout).to_string(),
+            String::from_utf8_lossy(&search.stdout).contains("foo 1.0"),
+        )
+    }
+}
+
+const FUTURE: &str = "Fri, 01 Jan 2100 00:00:00 UTC";
+
+#[test]
+fn valid_signature() {
+    let key = Key::generate("valid");
+    let setup = Setup::new(&key);
+    setup.publish(&key, PACKAGES, FUTURE);
+    let (output, available) = setup.update();
+    assert!(!output.contains("Skipping"), "{}", output);
+    assert!(available);
+}
+
+#[test]
+fn tampered_packages() {
+    let key = Key::generate("tampered");
+    let setup = Setup::new(&key);
+    setup.publish(&key, PACKAGES, FUTURE);
+    fs::write(
+        setup.repo().join("Packages"),
+        PACKAGES.replace("1.0", "6.6"),
+    )
+    .unwrap();
+    let (output, available) = setup.update();
+    assert!(
+        output.contains("Hash sum mismatch of Packages"),
+        "{}",
+        output
+    );
+    assert!(!available);
+}
+
+#[test]
+fn wrong_key() {
+    let trusted = Key::generate("trusted");
+    let other = Key::generate("other");
+    let setup = Setup::new(&trusted);
+    setup.publish(&other, PACKAGES, FUTURE);
+    let (output, available) = setup.update();
+    assert!(
+        output.contains("The signature couldn't be verified"),
+        "{}",
+        output
+    );
+    assert!(!available);
+}
+
+#[test]
+fn expired_release() {
+    let key = Key::generate("expired");
+    let setup = Setup::new(&key);
+    setup.publish(&key, PACKAGES, "Thu, 01 Jan 2015 00:00:00 UTC");
+    let (output, available) = setup.update();
+    assert!(output.contains("Release file expired"), "{}", output);
+    assert!(!available);
+}